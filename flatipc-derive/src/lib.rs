@@ -1,25 +1,539 @@
-use std::sync::atomic::AtomicUsize;
-
 use proc_macro::TokenStream;
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, ToTokens};
 use syn::{parse_macro_input, spanned::Spanned, DeriveInput};
 
-fn ast_hash(ast: &syn::DeriveInput) -> usize {
-    use std::hash::{Hash, Hasher};
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    ast.hash(&mut hasher);
-    let full_hash = hasher.finish();
+/// Append a canonical rendering of `tokens` to `out`. Tokens are separated by exactly
+/// one space so the result doesn't depend on how the source was formatted.
+fn push_canonical_tokens(tokens: proc_macro2::TokenStream, out: &mut String) {
+    for token in tokens {
+        if !out.is_empty() && !out.ends_with(' ') {
+            out.push(' ');
+        }
+        match token {
+            proc_macro2::TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    proc_macro2::Delimiter::Parenthesis => ("(", ")"),
+                    proc_macro2::Delimiter::Brace => ("{", "}"),
+                    proc_macro2::Delimiter::Bracket => ("[", "]"),
+                    proc_macro2::Delimiter::None => ("", ""),
+                };
+                out.push_str(open);
+                push_canonical_tokens(group.stream(), out);
+                if !out.ends_with(' ') {
+                    out.push(' ');
+                }
+                out.push_str(close);
+            }
+            proc_macro2::TokenTree::Ident(ident) => out.push_str(&ident.to_string()),
+            proc_macro2::TokenTree::Punct(punct) => out.push(punct.as_char()),
+            proc_macro2::TokenTree::Literal(literal) => out.push_str(&literal.to_string()),
+        }
+    }
+}
+
+fn push_fields_description(fields: &syn::Fields, out: &mut String) {
+    match fields {
+        syn::Fields::Named(fields) => {
+            out.push_str(" {");
+            for field in fields.named.iter() {
+                out.push(' ');
+                out.push_str(&field.ident.as_ref().unwrap().to_string());
+                out.push(',');
+            }
+            out.push_str(" }");
+        }
+        syn::Fields::Unnamed(fields) => {
+            out.push('(');
+            for _ in fields.unnamed.iter() {
+                out.push_str("_,");
+            }
+            out.push(')');
+        }
+        syn::Fields::Unit => {}
+    }
+}
+
+/// Describe the shape of a type as a string. This covers the type name, its `repr`,
+/// and the names of every field, but deliberately excludes doc comments, visibility,
+/// and other attributes so that cosmetic edits don't change the signature. The types
+/// of the fields are accounted for separately by `signature_hash`.
+fn type_description(ast: &syn::DeriveInput) -> String {
+    let mut description = String::new();
+    for attr in ast.attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        description.push_str("#[");
+        push_canonical_tokens(attr.meta.to_token_stream(), &mut description);
+        description.push_str("] ");
+    }
+    match &ast.data {
+        syn::Data::Struct(r#struct) => {
+            description.push_str("struct ");
+            description.push_str(&ast.ident.to_string());
+            push_fields_description(&r#struct.fields, &mut description);
+        }
+        syn::Data::Enum(r#enum) => {
+            description.push_str("enum ");
+            description.push_str(&ast.ident.to_string());
+            description.push_str(" {");
+            for variant in r#enum.variants.iter() {
+                description.push(' ');
+                description.push_str(&variant.ident.to_string());
+                push_fields_description(&variant.fields, &mut description);
+                if let Some((_, discriminant)) = &variant.discriminant {
+                    description.push_str(" = ");
+                    push_canonical_tokens(quote! { #discriminant }, &mut description);
+                }
+                description.push(',');
+            }
+            description.push_str(" }");
+        }
+        syn::Data::Union(r#union) => {
+            description.push_str("union ");
+            description.push_str(&ast.ident.to_string());
+            push_fields_description(&syn::Fields::Named(r#union.fields.clone()), &mut description);
+        }
+    }
+    description
+}
+
+/// Collect the signatures of every type that makes up `ty`. Tuples are
+/// not themselves `IpcSafe`, so their elements are visited individually.
+fn push_field_signatures(ty: &syn::Type, out: &mut Vec<proc_macro2::TokenStream>) {
+    match ty {
+        syn::Type::Tuple(tuple) => {
+            for ty in tuple.elems.iter() {
+                push_field_signatures(ty, out);
+            }
+        }
+        syn::Type::Group(group) => push_field_signatures(&group.elem, out),
+        syn::Type::Paren(paren) => push_field_signatures(&paren.elem, out),
+        _ => out.push(type_signature(ty)),
+    }
+}
+
+/// Generate a `u64` expression for the signature of `ty`. Arrays are mixed in the
+/// same way as the `IpcSafe` impl for `[T; N]`, which lets their elements be tuples.
+fn type_signature(ty: &syn::Type) -> proc_macro2::TokenStream {
+    match ty {
+        syn::Type::Tuple(tuple) => {
+            let elements = tuple.elems.iter().map(type_signature);
+            quote! {{
+                let mut hash = flatipc::signature::fnv1a(b"()");
+                #(hash = flatipc::signature::combine(hash, #elements);)*
+                hash
+            }}
+        }
+        syn::Type::Array(array) => {
+            let (element, len) = (type_signature(&array.elem), &array.len);
+            quote! {
+                flatipc::signature::combine(
+                    flatipc::signature::combine(flatipc::signature::fnv1a(b"[]"), #element),
+                    (#len) as u64,
+                )
+            }
+        }
+        syn::Type::Group(group) => type_signature(&group.elem),
+        syn::Type::Paren(paren) => type_signature(&paren.elem),
+        _ => quote! { <#ty as flatipc::IpcSafe>::SIGNATURE },
+    }
+}
 
-    #[cfg(target_pointer_width = "64")]
-    {
-        full_hash as usize
+/// Generate a `u64` expression that evaluates to the signature of the type at compile time.
+/// This mixes the description of the type with the signature of the type of every field.
+fn signature_hash(ast: &syn::DeriveInput) -> proc_macro2::TokenStream {
+    let description = syn::LitByteStr::new(type_description(ast).as_bytes(), ast.ident.span());
+    let mut field_signatures = vec![];
+    let fields: Vec<&syn::Field> = match &ast.data {
+        syn::Data::Struct(r#struct) => r#struct.fields.iter().collect(),
+        syn::Data::Enum(r#enum) => r#enum.variants.iter().flat_map(|v| v.fields.iter()).collect(),
+        syn::Data::Union(r#union) => r#union.fields.named.iter().collect(),
+    };
+    for field in fields {
+        push_field_signatures(&field.ty, &mut field_signatures);
     }
-    #[cfg(target_pointer_width = "32")]
-    {
-        (((full_hash >> 32) as u32) ^ (full_hash as u32)) as usize
+    quote! {
+        {
+            let mut hash = flatipc::signature::fnv1a(#description);
+            #(hash = flatipc::signature::combine(hash, #field_signatures);)*
+            hash
+        }
     }
-    #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
-    compile_error!("Unsupported target_pointer_width");
+}
+
+/// Options that may be specified on a `#[derive(Ipc)]` type with `#[flatipc(...)]`.
+#[derive(Default)]
+struct IpcAttributes {
+    /// A protocol version that is mixed into the computed signature.
+    version: Option<syn::LitInt>,
+
+    /// A signature to use in place of the computed one.
+    signature: Option<syn::LitInt>,
+
+    /// The alignment and granularity of the generated type, if not the default.
+    page_size: Option<syn::LitInt>,
+}
+
+/// The page size used when `#[flatipc(page_size = ...)]` is not specified. `repr(align)`
+/// needs a literal, so this can't refer to `flatipc::PAGE_SIZE`, and generated code
+/// instead asserts that the two agree.
+const DEFAULT_PAGE_SIZE: usize = if cfg!(feature = "page-size-16k") { 16384 } else { 4096 };
+
+/// The smallest page size of any target, below which an object would share its
+/// pages with whatever memory surrounds it.
+const MIN_PAGE_SIZE: usize = 4096;
+
+fn parse_ipc_attributes(ast: &DeriveInput) -> Result<IpcAttributes, proc_macro2::TokenStream> {
+    let mut attributes = IpcAttributes::default();
+    for attr in ast.attrs.iter() {
+        if !attr.path().is_ident("flatipc") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("version") {
+                let version: syn::LitInt = meta.value()?.parse()?;
+                version.base10_parse::<u64>()?;
+                attributes.version = Some(version);
+                Ok(())
+            } else if meta.path.is_ident("signature") {
+                let signature: syn::LitInt = meta.value()?.parse()?;
+                signature.base10_parse::<u64>()?;
+                attributes.signature = Some(signature);
+                Ok(())
+            } else if meta.path.is_ident("page_size") {
+                let page_size: syn::LitInt = meta.value()?.parse()?;
+                let value = page_size.base10_parse::<usize>()?;
+                if !value.is_power_of_two() || value < MIN_PAGE_SIZE {
+                    return Err(syn::Error::new(
+                        page_size.span(),
+                        format!("`page_size` must be a power of two of at least {}", MIN_PAGE_SIZE),
+                    ));
+                }
+                attributes.page_size = Some(page_size);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported flatipc attribute"))
+            }
+        })
+        .map_err(|e| e.to_compile_error())?;
+    }
+    if let (Some(_), Some(signature)) = (&attributes.version, &attributes.signature) {
+        return Err(syn::Error::new(
+            signature.span(),
+            "`version` has no effect when `signature` is specified; remove one of them",
+        )
+        .to_compile_error());
+    }
+    Ok(attributes)
+}
+
+/// Generate an expression that evaluates to the `usize` signature of an `Ipc` type at compile time.
+/// A pinned `signature` is used verbatim, otherwise the signature is computed from the type and
+/// mixed with the `version`, if any.
+fn signature_expr(ast: &syn::DeriveInput, attributes: &IpcAttributes) -> proc_macro2::TokenStream {
+    if let Some(signature) = &attributes.signature {
+        // Unsuffixed, so that a value too large for the target's `usize` is a compile error
+        let signature = proc_macro2::Literal::u64_unsuffixed(signature.base10_parse::<u64>().unwrap());
+        return quote! { #signature };
+    }
+    let hash = match &attributes.version {
+        Some(version) => {
+            let hash = signature_hash(ast);
+            quote! { flatipc::signature::combine(#hash, #version as u64) }
+        }
+        None => signature_hash(ast),
+    };
+    quote! { flatipc::signature::fold(#hash) }
+}
+
+/// Generate code that validates the value at `place`, which is an expression of type
+/// `*const #ty`. Tuples don't implement `Validate`, so each element is visited instead,
+/// and arrays are visited element by element in case they hold tuples. The types that
+/// are validated are added to `bounds`.
+fn validate_place(
+    place: proc_macro2::TokenStream,
+    ty: &syn::Type,
+    name: &str,
+    bounds: &mut Vec<syn::WherePredicate>,
+) -> proc_macro2::TokenStream {
+    match ty {
+        syn::Type::Tuple(tuple) => {
+            let elements: Vec<_> = tuple
+                .elems
+                .iter()
+                .enumerate()
+                .map(|(index, ty)| {
+                    let index = syn::Index::from(index);
+                    validate_place(
+                        quote! { core::ptr::addr_of!((*#place).#index) },
+                        ty,
+                        &format!("{}.{}", name, index.index),
+                        bounds,
+                    )
+                })
+                .collect();
+            quote! { #(#elements)* }
+        }
+        syn::Type::Array(array) => {
+            let (elem, len) = (&array.elem, &array.len);
+            let element = validate_place(quote! { element }, elem, name, bounds);
+            quote! {
+                for index in 0..(#len) {
+                    let element = (#place as *const #elem).add(index);
+                    #element
+                }
+            }
+        }
+        syn::Type::Group(group) => validate_place(place, &group.elem, name, bounds),
+        syn::Type::Paren(paren) => validate_place(place, &paren.elem, name, bounds),
+        _ => {
+            bounds.push(syn::parse_quote!(for<'__flatipc> #ty: flatipc::Validate));
+            quote! { flatipc::validate::field::<#ty>(#place, #name)?; }
+        }
+    }
+}
+
+/// Generate the `Validate::NICHE` of a field of type `ty`. Tuples have no `Validate`,
+/// so where `None` is stored in one isn't known.
+fn niche_of(ty: &syn::Type) -> proc_macro2::TokenStream {
+    match ty {
+        syn::Type::Tuple(_) => quote! { None },
+        syn::Type::Array(array) => {
+            let (elem, len) = (niche_of(&array.elem), &array.len);
+            quote! { if (#len) == 0 { None } else { #elem } }
+        }
+        syn::Type::Group(group) => niche_of(&group.elem),
+        syn::Type::Paren(paren) => niche_of(&paren.elem),
+        _ => quote! { <#ty as flatipc::Validate>::NICHE },
+    }
+}
+
+/// Implement `Validate`. Like `DefaultInPlace`, the bounds on the types of the fields are
+/// higher-ranked, so a type with a field that can't be validated, such as a `Result`,
+/// is left without `from_slice_checked()` rather than failing to compile.
+fn generate_validate(ast: &DeriveInput) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    let ident = &ast.ident;
+    let mut bounds = vec![];
+    let mut niche = quote! {};
+    let body = match &ast.data {
+        syn::Data::Struct(r#struct) => {
+            // `None` is stored in whichever field rustc finds the largest niche in
+            let niches = r#struct.fields.iter().enumerate().map(|(index, field)| {
+                let member = match &field.ident {
+                    Some(field_ident) => quote! { #field_ident },
+                    None => {
+                        let index = syn::Index::from(index);
+                        quote! { #index }
+                    }
+                };
+                let (ty, field_niche) = (&field.ty, niche_of(&field.ty));
+                quote! {(
+                    core::mem::offset_of!(Self, #member),
+                    flatipc::validate::holds_niche::<Self, #ty>(core::mem::offset_of!(Self, #member)),
+                    #field_niche,
+                )}
+            });
+            niche = quote! {
+                const NICHE: Option<(usize, usize)> = flatipc::validate::struct_niche(&[#(#niches),*]);
+            };
+            let fields: Vec<_> = r#struct
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(index, field)| {
+                    let (member, name) = match &field.ident {
+                        Some(field_ident) => (quote! { #field_ident }, format!("{}.{}", ident, field_ident)),
+                        None => {
+                            let index = syn::Index::from(index);
+                            (quote! { #index }, format!("{}.{}", ident, index.index))
+                        }
+                    };
+                    let place = quote! { core::ptr::addr_of!((*ptr).#member) };
+                    validate_place(place, &field.ty, &name, &mut bounds)
+                })
+                .collect();
+            quote! {
+                unsafe {
+                    #(#fields)*
+                }
+                Ok(())
+            }
+        }
+        syn::Data::Enum(r#enum) if r#enum.variants.iter().all(|v| v.fields.is_empty()) => {
+            let variants = r#enum.variants.iter().map(|v| &v.ident);
+            // The enum is nothing but its discriminant
+            niche = quote! { const NICHE: Option<(usize, usize)> = Some((0, core::mem::size_of::<Self>())); };
+            quote! {
+                unsafe { flatipc::validate::discriminant(ptr, &[#(Self::#variants),*]) }
+            }
+        }
+        syn::Data::Enum(r#enum) => {
+            // Matching on the enum would read a discriminant that hasn't been checked, so
+            // the tag is compared as a fieldless enum with the same `repr` and the fields
+            // are found using the layout that RFC 2195 specifies for that `repr`. Without
+            // one the layout is unspecified, so the enum can't be validated.
+            let (repr_c, primitive) = enum_repr(ast)?;
+            let tag_repr = match primitive {
+                Some(primitive) => quote! { #primitive },
+                None if repr_c => quote! { C },
+                None => return Ok(quote! {}),
+            };
+            let variant_idents: Vec<_> = r#enum.variants.iter().map(|v| &v.ident).collect();
+            let discriminants = r#enum.variants.iter().map(|v| match &v.discriminant {
+                Some((eq, expr)) => quote! { #eq #expr },
+                None => quote! {},
+            });
+            // `repr(C)` places every variant in a union after the tag, while a primitive
+            // `repr` alone lays each variant out as a `repr(C)` struct that starts with it.
+            let start = if repr_c {
+                let field_types = r#enum.variants.iter().flat_map(|v| v.fields.iter().map(|f| &f.ty));
+                quote! {
+                    let align = 1 #(.max(core::mem::align_of::<#field_types>()))*;
+                    (core::mem::size_of::<__FlatipcTag>() + align - 1) & !(align - 1)
+                }
+            } else {
+                quote! { core::mem::size_of::<__FlatipcTag>() }
+            };
+            let mut arms = vec![];
+            for (index, variant) in r#enum.variants.iter().enumerate() {
+                let variant_ident = &variant.ident;
+                let mut checks = vec![];
+                for (index, field) in variant.fields.iter().enumerate() {
+                    let ty = &field.ty;
+                    let name = match &field.ident {
+                        Some(field_ident) => format!("{}::{}.{}", ident, variant_ident, field_ident),
+                        None => format!("{}::{}.{}", ident, variant_ident, index),
+                    };
+                    let check = validate_place(quote! { field }, ty, &name, &mut bounds);
+                    checks.push(quote! {
+                        let align = core::mem::align_of::<#ty>();
+                        offset = (offset + align - 1) & !(align - 1);
+                        let field = base.add(offset) as *const #ty;
+                        #check
+                        offset += core::mem::size_of::<#ty>();
+                    });
+                }
+                arms.push(if variant.fields.is_empty() {
+                    quote! { #index => {} }
+                } else {
+                    quote! { #index => { let mut offset = start; #(#checks)* } }
+                });
+            }
+            let tag = quote! {
+                #[allow(dead_code)]
+                #[derive(Clone, Copy)]
+                #[repr(#tag_repr)]
+                enum __FlatipcTag {
+                    #(#variant_idents #discriminants),*
+                }
+            };
+            // `None` is stored in the tag, which is at the start of the enum
+            niche = quote! {
+                const NICHE: Option<(usize, usize)> = {
+                    #tag
+                    Some((0, core::mem::size_of::<__FlatipcTag>()))
+                };
+            };
+            quote! {
+                #tag
+                let variant = unsafe {
+                    flatipc::validate::variant_index(
+                        ptr as *const __FlatipcTag,
+                        &[#(__FlatipcTag::#variant_idents),*],
+                    )
+                }?;
+                let base = ptr as *const u8;
+                let start = { #start };
+                unsafe {
+                    match variant {
+                        #(#arms)*
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(())
+            }
+        }
+        // The active field of a union is not known, so there is nothing to check.
+        syn::Data::Union(_) => quote! { Ok(()) },
+    };
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let mut predicates: Vec<syn::WherePredicate> =
+        where_clause.map(|clause| clause.predicates.iter().cloned().collect()).unwrap_or_default();
+    predicates.extend(bounds);
+    Ok(quote! {
+        unsafe impl #impl_generics flatipc::Validate for #ident #ty_generics where #(#predicates),* {
+            #[allow(unused_unsafe, unused_assignments)]
+            unsafe fn validate(ptr: *const Self) -> Result<(), flatipc::DecodeError> {
+                #body
+            }
+            #niche
+        }
+    })
+}
+
+/// Implement `DefaultInPlace` by writing every field of a struct in place, or an
+/// enum's `Default` value as a whole. The bounds are higher-ranked so that they are
+/// only checked where the trait is used, leaving types whose fields have no default
+/// without an implementation rather than failing to compile.
+fn generate_default_in_place(ast: &DeriveInput) -> proc_macro2::TokenStream {
+    let ident = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let mut predicates: Vec<syn::WherePredicate> =
+        where_clause.map(|clause| clause.predicates.iter().cloned().collect()).unwrap_or_default();
+    let body = match &ast.data {
+        syn::Data::Struct(r#struct) => {
+            let fields = r#struct.fields.iter().enumerate().map(|(index, field)| {
+                let ty = &field.ty;
+                predicates.push(syn::parse_quote!(for<'__flatipc> #ty: flatipc::DefaultInPlace));
+                let member = match &field.ident {
+                    Some(field_ident) => quote! { #field_ident },
+                    None => {
+                        let index = syn::Index::from(index);
+                        quote! { #index }
+                    }
+                };
+                quote! {
+                    let field = core::ptr::addr_of_mut!((*ptr).#member);
+                    <#ty as flatipc::DefaultInPlace>::default_in_place(field);
+                }
+            });
+            let fields: Vec<_> = fields.collect();
+            quote! { let _ = ptr; unsafe { #(#fields)* } }
+        }
+        syn::Data::Enum(_) => {
+            predicates.push(syn::parse_quote!(for<'__flatipc> #ident #ty_generics: Default));
+            quote! { unsafe { ptr.write(Default::default()) } }
+        }
+        syn::Data::Union(_) => return quote! {},
+    };
+    quote! {
+        unsafe impl #impl_generics flatipc::DefaultInPlace for #ident #ty_generics where #(#predicates),* {
+            #[allow(unused_unsafe)]
+            unsafe fn default_in_place(ptr: *mut Self) {
+                #body
+            }
+        }
+    }
+}
+
+/// Return whether an enum is `repr(C)`, and the primitive type given to its `repr`.
+fn enum_repr(ast: &DeriveInput) -> Result<(bool, Option<syn::Ident>), proc_macro2::TokenStream> {
+    const PRIMITIVES: &[&str] =
+        &["u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize"];
+    let (mut repr_c, mut primitive) = (false, None);
+    for attr in ast.attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("C") {
+                repr_c = true;
+            } else if PRIMITIVES.iter().any(|name| meta.path.is_ident(name)) {
+                primitive = meta.path.get_ident().cloned();
+            } else if meta.input.peek(syn::token::Paren) {
+                // Skip the arguments of `align(N)` and `packed(N)`
+                meta.input.parse::<proc_macro2::Group>()?;
+            }
+            Ok(())
+        })
+        .map_err(|e| e.to_compile_error())?;
+    }
+    Ok((repr_c, primitive))
 }
 
 #[proc_macro_derive(IpcSafe)]
@@ -32,21 +546,198 @@ fn derive_transmittable_inner(
     ast: DeriveInput,
 ) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
     let ident = ast.ident.clone();
+    let generics = with_bound(&ast.generics, syn::parse_quote!(flatipc::IpcSafe));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     let transmittable_checks = match &ast.data {
         syn::Data::Struct(r#struct) => generate_transmittable_checks_struct(&ast, r#struct)?,
         syn::Data::Enum(r#enum) => generate_transmittable_checks_enum(&ast, r#enum)?,
         syn::Data::Union(r#union) => generate_transmittable_checks_union(&ast, r#union)?,
     };
+    let signature = signature_hash(&ast);
+    let validate = generate_validate(&ast)?;
+    let default_in_place = generate_default_in_place(&ast);
     let result = quote! {
         #transmittable_checks
+        #validate
+        #default_in_place
 
-        unsafe impl flatipc::IpcSafe for #ident {}
+        unsafe impl #impl_generics flatipc::IpcSafe for #ident #ty_generics #where_clause {
+            const SIGNATURE: u64 = #signature;
+        }
     };
 
     Ok(result)
 }
 
-#[proc_macro_derive(Ipc)]
+#[proc_macro_derive(IpcScalar)]
+pub fn derive_ipc_scalar(ts: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(ts as syn::DeriveInput);
+    derive_ipc_scalar_inner(ast).unwrap_or_else(|e| e).into()
+}
+
+fn derive_ipc_scalar_inner(ast: DeriveInput) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    // The padding check below is a free constant, which can't refer to generic parameters
+    if !ast.generics.params.is_empty() {
+        return Err(
+            syn::Error::new(ast.generics.span(), "IpcScalar types may not be generic").to_compile_error()
+        );
+    }
+    let ident = &ast.ident;
+    let field_types: Vec<&syn::Type> = match &ast.data {
+        syn::Data::Struct(r#struct) => {
+            ensure_valid_repr(&ast)?;
+            r#struct.fields.iter().map(|field| &field.ty).collect()
+        }
+        // A fieldless enum is nothing but its discriminant, which has no padding
+        syn::Data::Enum(r#enum) if r#enum.variants.iter().all(|v| v.fields.is_empty()) => Vec::new(),
+        syn::Data::Enum(r#enum) => {
+            return Err(syn::Error::new(r#enum.enum_token.span(), "IpcScalar enums may not have fields")
+                .to_compile_error())
+        }
+        syn::Data::Union(r#union) => {
+            return Err(syn::Error::new(r#union.union_token.span(), "IpcScalar does not support unions")
+                .to_compile_error())
+        }
+    };
+
+    // Every byte of the value is copied into the arguments, so each field must itself be
+    // free of padding, and there may be no padding between or after the fields.
+    let padding_check = if field_types.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            assert!(
+                core::mem::size_of::<#ident>() == 0 #(+ core::mem::size_of::<#field_types>())*,
+                concat!("`", stringify!(#ident), "` contains padding and can't be sent as a scalar")
+            );
+        }
+    };
+    let surrounding_function = format_ident!("ensure_members_are_scalar_for_{}", ident);
+    Ok(quote! {
+        #[allow(non_snake_case, dead_code)]
+        fn #surrounding_function() {
+            pub fn ensure_is_scalar<T: flatipc::IpcScalar>() {}
+            #(ensure_is_scalar::<#field_types>();)*
+        }
+
+        const _: () = {
+            #padding_check
+            assert!(
+                core::mem::size_of::<#ident>() <= flatipc::scalar::SCALAR_SIZE,
+                concat!("`", stringify!(#ident), "` is too large to be sent as a scalar")
+            );
+        };
+
+        unsafe impl flatipc::IpcScalar for #ident {}
+    })
+}
+
+/// Hash a variant name into an opcode using 32-bit FNV-1a, so the opcode doesn't
+/// change when variants are added, removed, or reordered.
+fn opcode_hash(name: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in name.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Parse an explicit opcode. Hashed opcodes are 32 bits wide, and explicit ones are
+/// limited to the same range so that every opcode fits in a `usize` on Xous.
+fn parse_opcode(expr: &syn::Expr) -> Result<u32, proc_macro2::TokenStream> {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(value), .. }) => value
+            .base10_parse::<u32>()
+            .map_err(|_| syn::Error::new(value.span(), "opcodes must fit in a u32").to_compile_error()),
+        _ => Err(syn::Error::new(expr.span(), "opcodes must be integer literals").to_compile_error()),
+    }
+}
+
+/// Generate the conversions between an opcode enum and `usize`, given each variant
+/// and its explicit opcode if it has one. Variants without one are numbered with a
+/// hash of their name.
+fn opcode_conversions(
+    ident: &syn::Ident,
+    variants: &[(&syn::Ident, Option<&syn::Expr>)],
+) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    // Mixing the two would make it unclear which opcodes are part of the protocol
+    let explicit = variants.iter().filter(|(_, opcode)| opcode.is_some()).count();
+    if explicit != 0 && explicit != variants.len() {
+        return Err(syn::Error::new(
+            ident.span(),
+            "either every variant or none of them must have an explicit opcode",
+        )
+        .to_compile_error());
+    }
+
+    let mut opcodes: Vec<u32> = vec![];
+    for (index, (variant, opcode)) in variants.iter().enumerate() {
+        let opcode = match opcode {
+            Some(expr) => parse_opcode(expr)?,
+            None => opcode_hash(&variant.to_string()),
+        };
+        if let Some(other) = opcodes.iter().position(|&other| other == opcode) {
+            let message = format!("`{}` has the same opcode as `{}`", variant, variants[other].0);
+            return Err(syn::Error::new(variants[index].0.span(), message).to_compile_error());
+        }
+        opcodes.push(opcode);
+    }
+    let opcodes: Vec<_> =
+        opcodes.iter().map(|&opcode| proc_macro2::Literal::usize_suffixed(opcode as usize)).collect();
+    let variants: Vec<_> = variants.iter().map(|(variant, _)| variant).collect();
+
+    Ok(quote! {
+        impl core::convert::TryFrom<usize> for #ident {
+            type Error = usize;
+
+            fn try_from(value: usize) -> Result<Self, usize> {
+                match value {
+                    #(#opcodes => Ok(#ident::#variants),)*
+                    other => Err(other),
+                }
+            }
+        }
+
+        impl From<#ident> for usize {
+            fn from(opcode: #ident) -> usize {
+                match opcode {
+                    #(#ident::#variants => #opcodes,)*
+                }
+            }
+        }
+    })
+}
+
+/// Number the variants of a fieldless enum with stable opcodes. Each variant is either
+/// given an explicit opcode as its discriminant, or, if none of them are, an opcode
+/// hashed from its name. Opcodes are converted with `usize::from()` and `try_from()`.
+#[proc_macro_derive(Opcodes)]
+pub fn derive_opcodes(ts: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(ts as syn::DeriveInput);
+    derive_opcodes_inner(ast).unwrap_or_else(|e| e).into()
+}
+
+fn derive_opcodes_inner(ast: DeriveInput) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    let syn::Data::Enum(r#enum) = &ast.data else {
+        return Err(syn::Error::new(ast.span(), "Opcodes can only be derived for enums").to_compile_error());
+    };
+    if !ast.generics.params.is_empty() {
+        return Err(syn::Error::new(ast.generics.span(), "Opcodes may not be generic").to_compile_error());
+    }
+    let mut variants = vec![];
+    for variant in r#enum.variants.iter() {
+        if !variant.fields.is_empty() {
+            return Err(
+                syn::Error::new(variant.fields.span(), "opcodes may not have fields").to_compile_error()
+            );
+        }
+        variants.push((&variant.ident, variant.discriminant.as_ref().map(|(_, expr)| expr)));
+    }
+    opcode_conversions(&ast.ident, &variants)
+}
+
+#[proc_macro_derive(Ipc, attributes(flatipc))]
 pub fn derive_ipc(ts: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(ts as syn::DeriveInput);
     derive_ipc_inner(ast).unwrap_or_else(|e| e).into()
@@ -62,9 +753,13 @@ fn derive_ipc_inner(ast: DeriveInput) -> Result<proc_macro2::TokenStream, proc_m
         syn::Data::Union(r#union) => generate_transmittable_checks_union(&ast, r#union)?,
     };
 
+    let validate = generate_validate(&ast)?;
+    let default_in_place = generate_default_in_place(&ast);
     let ipc_struct = generate_ipc_struct(&ast)?;
     Ok(quote! {
         #transmittable_checks
+        #validate
+        #default_in_place
         #ipc_struct
     })
 }
@@ -76,6 +771,8 @@ fn ensure_valid_repr(ast: &DeriveInput) -> Result<(), proc_macro2::TokenStream>
             attr.parse_nested_meta(|meta| {
                 if meta.path.is_ident("C") {
                     repr_c = true;
+                } else if meta.input.peek(syn::token::Paren) {
+                    meta.input.parse::<proc_macro2::Group>()?;
                 }
                 Ok(())
             })
@@ -111,18 +808,20 @@ fn type_to_string(ty: &syn::Type) -> String {
     }
 }
 
+/// Return a copy of `generics` where every type parameter is additionally bound by `bound`.
+fn with_bound(generics: &syn::Generics, bound: syn::TypeParamBound) -> syn::Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(bound.clone());
+    }
+    generics
+}
+
 fn ensure_type_exists_for(ty: &syn::Type) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
     match ty {
-        syn::Type::Path(_) => {
-            static ATOMIC_INDEX: AtomicUsize = AtomicUsize::new(0);
-            let fn_name = format_ident!(
-                "assert_type_exists_for_parameter_{}",
-                ATOMIC_INDEX.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
-            );
-            Ok(quote! {
-                fn #fn_name (_var: #ty) { ensure_is_transmittable::<#ty>(); }
-            })
-        }
+        syn::Type::Path(_) => Ok(quote! {
+            ensure_is_transmittable::<#ty>();
+        }),
         syn::Type::Tuple(tuple) => {
             let mut check_functions = vec![];
             for ty in tuple.elems.iter() {
@@ -133,6 +832,8 @@ fn ensure_type_exists_for(ty: &syn::Type) -> Result<proc_macro2::TokenStream, pr
             })
         }
         syn::Type::Array(array) => ensure_type_exists_for(&array.elem),
+        syn::Type::Group(group) => ensure_type_exists_for(&group.elem),
+        syn::Type::Paren(paren) => ensure_type_exists_for(&paren.elem),
         _ => Err(syn::Error::new(ty.span(), format!("The type `{}` is unsupported", type_to_string(ty)))
             .to_compile_error()),
     }
@@ -145,6 +846,8 @@ fn generate_transmittable_checks_enum(
     let mut variants = Vec::new();
 
     let surrounding_function = format_ident!("ensure_members_are_transmittable_for_{}", ast.ident);
+    let generics = with_bound(&ast.generics, syn::parse_quote!(flatipc::IpcSafe));
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     for variant in &enm.variants {
         let fields = match &variant.fields {
             syn::Fields::Named(fields) => {
@@ -171,7 +874,7 @@ fn generate_transmittable_checks_enum(
 
     Ok(quote! {
         #[allow(non_snake_case, dead_code)]
-        fn #surrounding_function () {
+        fn #surrounding_function #impl_generics () #where_clause {
             pub fn ensure_is_transmittable<T: flatipc::IpcSafe>() {}
             #(#variants)*
         }
@@ -184,6 +887,8 @@ fn generate_transmittable_checks_struct(
     strct: &syn::DataStruct,
 ) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
     let surrounding_function = format_ident!("ensure_members_are_transmittable_for_{}", ast.ident);
+    let generics = with_bound(&ast.generics, syn::parse_quote!(flatipc::IpcSafe));
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     let fields = match &strct.fields {
         syn::Fields::Named(fields) => fields.named.iter().map(|f| ensure_type_exists_for(&f.ty)).collect(),
         syn::Fields::Unnamed(fields) => {
@@ -200,7 +905,7 @@ fn generate_transmittable_checks_struct(
     }
     Ok(quote! {
         #[allow(non_snake_case, dead_code)]
-        fn #surrounding_function () {
+        fn #surrounding_function #impl_generics () #where_clause {
             pub fn ensure_is_transmittable<T: flatipc::IpcSafe>() {}
             #(#vetted_fields)*
         }
@@ -212,6 +917,8 @@ fn generate_transmittable_checks_union(
     unn: &syn::DataUnion,
 ) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
     let surrounding_function = format_ident!("ensure_members_are_transmittable_for_{}", ast.ident);
+    let generics = with_bound(&ast.generics, syn::parse_quote!(flatipc::IpcSafe));
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     let fields: Vec<Result<proc_macro2::TokenStream, proc_macro2::TokenStream>> =
         unn.fields.named.iter().map(|f| ensure_type_exists_for(&f.ty)).collect();
 
@@ -224,7 +931,7 @@ fn generate_transmittable_checks_union(
     }
     Ok(quote! {
         #[allow(non_snake_case, dead_code)]
-        fn #surrounding_function () {
+        fn #surrounding_function #impl_generics () #where_clause {
             pub fn ensure_is_transmittable<T: flatipc::IpcSafe>() {}
             #(#vetted_fields)*
         }
@@ -235,201 +942,223 @@ fn generate_ipc_struct(ast: &DeriveInput) -> Result<proc_macro2::TokenStream, pr
     let visibility = ast.vis.clone();
     let ident = ast.ident.clone();
     let ipc_ident = format_ident!("Ipc{}", ast.ident);
-    let ident_size = quote! { core::mem::size_of::< #ident >() };
-    let padded_size = quote! { (#ident_size + (4096 - 1)) & !(4096 - 1) };
-    let padding_size = quote! { #padded_size - #ident_size };
-    let hash = ast_hash(ast);
-
-    let build_message = quote! {
-        use xous::definitions::{MemoryMessage, MemoryAddress, MemoryRange};
-        let mut buf = unsafe { MemoryRange::new(data.as_ptr() as usize, data.len()) }.unwrap();
-        let msg = MemoryMessage {
-            id: opcode,
-            buf,
-            offset: MemoryAddress::new(signature),
-            valid: None,
-        };
+    let generics = with_bound(&ast.generics, syn::parse_quote!(flatipc::IpcSafe));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let attributes = parse_ipc_attributes(ast)?;
+    let page_size = match &attributes.page_size {
+        Some(page_size) => page_size.base10_parse::<usize>().unwrap(),
+        None => DEFAULT_PAGE_SIZE,
     };
-
-    let lend = if cfg!(feature = "xous") {
-        quote! {
-            #build_message
-            xous::send_message(connection, xous::Message::MutableBorrow(msg))?;
-        }
-    } else {
-        quote! {
-            flatipc::backend::mock::IPC_MACHINE.lock().unwrap().lend(connection, opcode, signature, 0, &data);
-        }
+    // `repr(align)` needs a literal without a suffix
+    let page_size = syn::LitInt::new(&page_size.to_string(), ident.span());
+    let page_size_check = match &attributes.page_size {
+        Some(_) => quote! {},
+        None => quote! {
+            const _: () = assert!(
+                #page_size == flatipc::PAGE_SIZE,
+                "the `page-size-16k` features of flatipc and flatipc-derive disagree"
+            );
+        },
     };
+    let ident_size = quote! { core::mem::size_of::< #ident #ty_generics >() };
+    let padded_size = quote! { (#ident_size + (#page_size - 1)) & !(#page_size - 1) };
+    let padding_size = quote! { #padded_size - #ident_size };
 
-    let try_lend = if cfg!(feature = "xous") {
-        quote! {
-            #build_message
-            xous::try_send_message(connection, xous::Message::MutableBorrow(msg))?;
-        }
+    // The length of an array can't depend on generic parameters, so generic types
+    // rely on the alignment of the struct to pad it out to a whole number of pages.
+    // That padding is uninitialised, so it is zeroed before the object is lent.
+    let (padding, padding_init, data, data_mut) = if ast.generics.params.is_empty() {
+        (
+            quote! { padding: [u8; #padding_size], },
+            quote! { padding: [0; #padding_size], },
+            quote! {{
+                let size = core::mem::size_of::<Self>();
+                unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size) }
+            }},
+            quote! {{
+                let size = core::mem::size_of::<Self>();
+                unsafe { core::slice::from_raw_parts_mut(self as *mut Self as *mut u8, size) }
+            }},
+        )
     } else {
-        quote! {
-            flatipc::backend::mock::IPC_MACHINE.lock().unwrap().lend(connection, opcode, signature, 0, &data);
-        }
+        (
+            quote! {},
+            quote! {},
+            // A shared reference can't be written to, so the original is copied instead
+            quote! {{
+                let mut buffer = flatipc::AlignedBuffer::with_alignment(
+                    core::mem::size_of::<Self>(),
+                    core::mem::align_of::<Self>(),
+                );
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        self as *const Self as *const u8,
+                        buffer.as_mut_ptr(),
+                        core::mem::size_of::<Self::Original>(),
+                    )
+                };
+                buffer
+            }},
+            quote! {
+                unsafe {
+                    let start = core::mem::size_of::<Self::Original>();
+                    let size = core::mem::size_of::<Self>();
+                    (self as *mut Self as *mut u8).add(start).write_bytes(0, size - start);
+                    core::slice::from_raw_parts_mut(self as *mut Self as *mut u8, size)
+                }
+            },
+        )
     };
+    let signature = signature_expr(ast, &attributes);
+    let type_name = ident.to_string();
 
-    let lend_mut = if cfg!(feature = "xous") {
-        quote! {
-            #build_message
-            xous::send_message(connection, xous::Message::MutableBorrow(msg))?;
+    // Verify that `data` is large enough, aligned, and of the correct type
+    // before it is turned into a reference.
+    let checks = quote! {
+        if data.len() < core::mem::size_of::<Self>() {
+            return Err(flatipc::DecodeError::TooShort {
+                needed: core::mem::size_of::<Self>(),
+                got: data.len(),
+            });
         }
-    } else {
-        quote! {
-            flatipc::backend::mock::IPC_MACHINE.lock().unwrap().lend_mut(connection, opcode, signature, 0, &mut data);
+        if (data.as_ptr() as usize) % core::mem::align_of::<Self>() != 0 {
+            return Err(flatipc::DecodeError::Misaligned);
         }
-    };
-
-    let try_lend_mut = if cfg!(feature = "xous") {
-        quote! {
-            #build_message
-            xous::try_send_message(connection, xous::Message::MutableBorrow(msg))?;
-        }
-    } else {
-        quote! {
-            flatipc::backend::mock::IPC_MACHINE.lock().unwrap().lend_mut(connection, opcode, signature, 0, &mut data);
+        if signature != Self::SIGNATURE {
+            return Err(flatipc::DecodeError::SignatureMismatch { expected: Self::SIGNATURE, got: signature });
         }
     };
 
-    let memory_messages = if cfg!(feature = "xous") {
-        quote! {
-            fn from_memory_message<'a>(msg: &'a xous::MemoryMessage) -> Option<&'a Self> {
-                if msg.buf.len() < core::mem::size_of::< #ipc_ident >() {
-                    return None;
-                }
-                let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
-                if signature != #hash {
-                    return None;
-                }
-                unsafe { Some(&*(msg.buf.as_ptr() as *const #ipc_ident)) }
-            }
-
-            fn from_memory_message_mut<'a>(msg: &'a mut xous::MemoryMessage) -> Option<&'a mut Self> {
-                if msg.buf.len() < core::mem::size_of::< #ipc_ident >() {
-                    return None;
-                }
-                let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
-                if signature != #hash {
-                    return None;
-                }
-                unsafe { Some(&mut *(msg.buf.as_mut_ptr() as *mut #ipc_ident)) }
-            }
-        }
-    } else {
-        quote! {}
-    };
-
     Ok(quote! {
-        #[repr(C, align(4096))]
-        #visibility struct #ipc_ident {
-            original: #ident,
-            padding: [u8; #padding_size],
+        #page_size_check
+
+        #[repr(C, align(#page_size))]
+        #visibility struct #ipc_ident #impl_generics #where_clause {
+            original: #ident #ty_generics,
+            #padding
         }
 
-        impl core::ops::Deref for #ipc_ident {
-            type Target = #ident ;
+        impl #impl_generics core::ops::Deref for #ipc_ident #ty_generics #where_clause {
+            type Target = #ident #ty_generics;
             fn deref(&self) -> &Self::Target {
                 &self.original
             }
         }
 
-        impl core::ops::DerefMut for #ipc_ident {
+        impl #impl_generics core::ops::DerefMut for #ipc_ident #ty_generics #where_clause {
             fn deref_mut(&mut self) -> &mut Self::Target {
                 &mut self.original
             }
         }
 
-        impl flatipc::IntoIpc for #ident {
-            type IpcType = #ipc_ident;
+        impl #impl_generics flatipc::IntoIpc for #ident #ty_generics #where_clause {
+            type IpcType = #ipc_ident #ty_generics;
             fn into_ipc(self) -> Self::IpcType {
                 #ipc_ident {
                     original: self,
-                    padding: [0; #padding_size],
+                    #padding_init
+                }
+            }
+
+            fn into_ipc_boxed(self) -> flatipc::IpcBox<Self::IpcType> {
+                unsafe {
+                    <Self::IpcType as flatipc::Ipc>::new_with(|original| {
+                        original.write(self);
+                    })
                 }
             }
         }
 
-        unsafe impl flatipc::Ipc for #ipc_ident {
-            type Original = #ident ;
+        unsafe impl #impl_generics flatipc::Ipc for #ipc_ident #ty_generics #where_clause {
+            type Original = #ident #ty_generics;
 
-            fn from_slice<'a>(data: &'a [u8], signature: usize) -> Option<&'a Self> {
-                if data.len() < core::mem::size_of::< #ipc_ident >() {
-                    return None;
-                }
-                if signature != #hash {
-                    return None;
-                }
-                unsafe { Some(&*(data.as_ptr() as *const u8 as *const #ipc_ident)) }
+            const SIGNATURE: usize = {
+                // The page size must also be the alignment of the IPC type
+                assert!(
+                    core::mem::align_of::<#ident #ty_generics>() <= #page_size,
+                    concat!("`", stringify!(#ident), "` is aligned to more than its page size")
+                );
+                #signature
+            };
+
+            fn try_from_slice(data: &[u8], signature: usize) -> Result<&Self, flatipc::DecodeError> {
+                #checks
+                unsafe { Ok(&*(data.as_ptr() as *const u8 as *const Self)) }
             }
 
-            unsafe fn from_buffer_unchecked<'a>(data: &'a [u8]) -> &'a Self {
-                &*(data.as_ptr() as *const u8 as *const #ipc_ident)
+            unsafe fn from_buffer_unchecked(data: &[u8]) -> &Self {
+                &*(data.as_ptr() as *const u8 as *const Self)
             }
 
-            fn from_slice_mut<'a>(data: &'a mut [u8], signature: usize) -> Option<&'a mut Self> {
-                if data.len() < core::mem::size_of::< #ipc_ident >() {
-                    return None;
-                }
-                if signature != #hash {
-                    return None;
-                }
-                unsafe { Some(&mut *(data.as_mut_ptr() as *mut u8 as *mut #ipc_ident)) }
+            fn try_from_slice_mut(data: &mut [u8], signature: usize) -> Result<&mut Self, flatipc::DecodeError> {
+                #checks
+                unsafe { Ok(&mut *(data.as_mut_ptr() as *mut u8 as *mut Self)) }
             }
 
-            unsafe fn from_buffer_mut_unchecked<'a>(data: &'a mut [u8]) -> &'a mut Self {
-                unsafe { &mut *(data.as_mut_ptr() as *mut u8 as *mut #ipc_ident) }
+            unsafe fn from_buffer_mut_unchecked(data: &mut [u8]) -> &mut Self {
+                unsafe { &mut *(data.as_mut_ptr() as *mut u8 as *mut Self) }
             }
 
-            fn lend(&self, connection: flatipc::CID, opcode: usize) -> Result<(), flatipc::Error> {
+            fn from_slice_checked(data: &[u8], signature: usize) -> Result<&Self, flatipc::DecodeError>
+            where
+                for<'__flatipc> #ident #ty_generics: flatipc::Validate,
+            {
+                #checks
+                unsafe { flatipc::validate::field(data.as_ptr() as *const Self::Original, #type_name) }?;
+                unsafe { Ok(&*(data.as_ptr() as *const u8 as *const Self)) }
+            }
+
+            fn from_slice_mut_checked(data: &mut [u8], signature: usize) -> Result<&mut Self, flatipc::DecodeError>
+            where
+                for<'__flatipc> #ident #ty_generics: flatipc::Validate,
+            {
+                #checks
+                unsafe { flatipc::validate::field(data.as_ptr() as *const Self::Original, #type_name) }?;
+                unsafe { Ok(&mut *(data.as_mut_ptr() as *mut u8 as *mut Self)) }
+            }
+
+            fn lend_with<Transport: flatipc::Transport + ?Sized>(
+                &self,
+                transport: &Transport,
+                connection: flatipc::CID,
+                opcode: impl Into<usize>,
+            ) -> Result<flatipc::LendResult, flatipc::Error> {
                 let signature = self.signature();
-                let data = unsafe {
-                    core::slice::from_raw_parts(
-                        self as *const #ipc_ident as *const u8,
-                        core::mem::size_of::< #ipc_ident >(),
-                    )
-                };
-                #lend
-                Ok(())
+                let data = #data;
+                transport.lend(connection, opcode.into(), signature, &data[..])
             }
 
-            fn try_lend(&self, connection: flatipc::CID, opcode: usize) -> Result<(), flatipc::Error> {
+            fn try_lend_with<Transport: flatipc::Transport + ?Sized>(
+                &self,
+                transport: &Transport,
+                connection: flatipc::CID,
+                opcode: impl Into<usize>,
+            ) -> Result<flatipc::LendResult, flatipc::Error> {
                 let signature = self.signature();
-                let data = unsafe {
-                    core::slice::from_raw_parts(
-                        self as *const #ipc_ident as *const u8,
-                        core::mem::size_of::< #ipc_ident >(),
-                    )
-                };
-                #try_lend
-                Ok(())
+                let data = #data;
+                transport.try_lend(connection, opcode.into(), signature, &data[..])
             }
 
-            fn lend_mut(&mut self, connection: flatipc::CID, opcode: usize) -> Result<(), flatipc::Error> {
+            fn lend_mut_with<Transport: flatipc::Transport + ?Sized>(
+                &mut self,
+                transport: &Transport,
+                connection: flatipc::CID,
+                opcode: impl Into<usize>,
+            ) -> Result<flatipc::LendResult, flatipc::Error> {
                 let signature = self.signature();
-                let mut data = unsafe {
-                    core::slice::from_raw_parts_mut(
-                        self as *mut #ipc_ident as *mut u8,
-                        #padded_size,
-                    )
-                };
-                #lend_mut
-                Ok(())
+                let data = #data_mut;
+                transport.lend_mut(connection, opcode.into(), signature, &mut data[..])
             }
 
-            fn try_lend_mut(&mut self, connection: flatipc::CID, opcode: usize) -> Result<(), flatipc::Error> {
+            fn try_lend_mut_with<Transport: flatipc::Transport + ?Sized>(
+                &mut self,
+                transport: &Transport,
+                connection: flatipc::CID,
+                opcode: impl Into<usize>,
+            ) -> Result<flatipc::LendResult, flatipc::Error> {
                 let signature = self.signature();
-                let mut data = unsafe {
-                    core::slice::from_raw_parts_mut(
-                        self as *mut #ipc_ident as *mut u8,
-                        #padded_size,
-                    )
-                };
-                #try_lend_mut
-                Ok(())
+                let data = #data_mut;
+                transport.try_lend_mut(connection, opcode.into(), signature, &mut data[..])
             }
 
             fn as_original(&self) -> &Self::Original {
@@ -444,11 +1173,412 @@ fn generate_ipc_struct(ast: &DeriveInput) -> Result<proc_macro2::TokenStream, pr
                 self.original
             }
 
-            fn signature(&self) -> usize {
-                #hash
+            unsafe fn new_with<F>(init: F) -> flatipc::IpcBox<Self>
+            where
+                F: FnOnce(&mut core::mem::MaybeUninit<Self::Original>),
+            {
+                // The padding is already zeroed, so only the original needs to be written.
+                let mut boxed = flatipc::IpcBox::<Self>::zeroed();
+                let original = core::ptr::addr_of_mut!((*boxed.as_mut_ptr()).original);
+                init(&mut *(original as *mut core::mem::MaybeUninit<Self::Original>));
+                boxed
             }
+        }
+    })
+}
 
-            #memory_messages
+/// How an argument to a service method is sent to the Server.
+enum ServiceArgument {
+    /// No argument, so an empty blocking scalar is sent.
+    None,
+    /// `&T`, which is lent as `T::IpcType`.
+    Lend(syn::Ident, syn::Type),
+    /// `&mut T`, which is lent mutably as `T::IpcType`.
+    LendMut(syn::Ident, syn::Type),
+    /// `T`, which is sent as a blocking scalar.
+    Scalar(syn::Ident, syn::Type),
+}
+
+struct ServiceMethod {
+    ident: syn::Ident,
+    variant: syn::Ident,
+    attrs: Vec<syn::Attribute>,
+    argument: ServiceArgument,
+    output: proc_macro2::TokenStream,
+}
+
+/// Turn `snake_case` into `CamelCase` for the variants of the opcode enum.
+fn camel_case(ident: &syn::Ident) -> syn::Ident {
+    let mut out = String::new();
+    for word in ident.to_string().split('_').filter(|word| !word.is_empty()) {
+        let mut chars = word.chars();
+        out.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+        out.extend(chars);
+    }
+    syn::Ident::new(&out, ident.span())
+}
+
+/// Remove the `#[opcode = N]` attribute from a service method, returning the opcode.
+fn take_opcode_attribute(
+    attrs: &mut Vec<syn::Attribute>,
+) -> Result<Option<syn::Expr>, proc_macro2::TokenStream> {
+    let Some(index) = attrs.iter().position(|attr| attr.path().is_ident("opcode")) else {
+        return Ok(None);
+    };
+    match attrs.remove(index).meta {
+        syn::Meta::NameValue(meta) => Ok(Some(meta.value)),
+        meta => Err(syn::Error::new(meta.span(), "expected `#[opcode = N]`").to_compile_error()),
+    }
+}
+
+fn parse_service_method(method: &syn::TraitItemFn) -> Result<ServiceMethod, proc_macro2::TokenStream> {
+    let sig = &method.sig;
+    if !sig.generics.params.is_empty() {
+        return Err(
+            syn::Error::new(sig.generics.span(), "service methods may not be generic").to_compile_error()
+        );
+    }
+    let mut inputs = sig.inputs.iter();
+    if !matches!(inputs.next(), Some(syn::FnArg::Receiver(receiver)) if receiver.reference.is_some()) {
+        return Err(syn::Error::new(sig.span(), "service methods must take `&self` or `&mut self`")
+            .to_compile_error());
+    }
+    let argument = match inputs.next() {
+        None => ServiceArgument::None,
+        Some(syn::FnArg::Typed(arg)) => {
+            let ident = match &*arg.pat {
+                syn::Pat::Ident(pat) => pat.ident.clone(),
+                _ => format_ident!("arg"),
+            };
+            match &*arg.ty {
+                syn::Type::Reference(reference) if reference.mutability.is_some() => {
+                    ServiceArgument::LendMut(ident, (*reference.elem).clone())
+                }
+                syn::Type::Reference(reference) => ServiceArgument::Lend(ident, (*reference.elem).clone()),
+                ty => ServiceArgument::Scalar(ident, ty.clone()),
+            }
+        }
+        Some(receiver) => {
+            return Err(syn::Error::new(receiver.span(), "unexpected receiver").to_compile_error());
+        }
+    };
+    if let Some(extra) = inputs.next() {
+        return Err(syn::Error::new(extra.span(), "service methods may take at most one argument")
+            .to_compile_error());
+    }
+    let output = match &sig.output {
+        syn::ReturnType::Default => quote! { () },
+        syn::ReturnType::Type(_, ty) => ty.to_token_stream(),
+    };
+    let attrs = method.attrs.iter().filter(|attr| attr.path().is_ident("doc")).cloned().collect();
+    Ok(ServiceMethod { ident: sig.ident.clone(), variant: camel_case(&sig.ident), attrs, argument, output })
+}
+
+/// Turn a trait into a service. This generates an opcode enum named `{Trait}Opcode`,
+/// a client stub named `{Trait}Client` with a method for each method of the trait,
+/// and a `dispatch()` method on the trait that decodes a request and calls the
+/// implementation. Opcodes are hashed from the method names as `#[derive(Opcodes)]`
+/// does, unless every method has an `#[opcode = N]` attribute. See `flatipc::service`
+/// for details.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, ts: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(proc_macro2::TokenStream::from(attr).span(), "service takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(ts as syn::ItemTrait);
+    service_inner(item).unwrap_or_else(|e| e).into()
+}
+
+fn service_inner(mut item: syn::ItemTrait) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new(item.generics.span(), "services may not be generic").to_compile_error());
+    }
+    let mut methods = vec![];
+    let mut opcodes = vec![];
+    for trait_item in item.items.iter_mut() {
+        match trait_item {
+            syn::TraitItem::Fn(method) => {
+                methods.push(parse_service_method(method)?);
+                opcodes.push(take_opcode_attribute(&mut method.attrs)?);
+            }
+            other => {
+                return Err(
+                    syn::Error::new(other.span(), "services may only contain methods").to_compile_error()
+                );
+            }
+        }
+    }
+    if methods.is_empty() {
+        return Err(
+            syn::Error::new(item.ident.span(), "services must have at least one method").to_compile_error()
+        );
+    }
+
+    let vis = &item.vis;
+    let ident = &item.ident;
+    let opcode = format_ident!("{}Opcode", ident);
+    let client = format_ident!("{}Client", ident);
+    let opcode_doc = format!("The opcodes of the methods of `{}`.", ident);
+    let variants: Vec<_> = methods.iter().map(|method| &method.variant).collect();
+    let numbered: Vec<_> =
+        variants.iter().zip(&opcodes).map(|(&variant, opcode)| (variant, opcode.as_ref())).collect();
+    let conversions = opcode_conversions(&opcode, &numbered)?;
+    let opcode_enum = quote! {
+        #[doc = #opcode_doc]
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        #vis enum #opcode {
+            #(#variants,)*
+        }
+
+        #conversions
+    };
+
+    let dispatch_arms = methods.iter().map(|method| {
+        let (name, variant) = (&method.ident, &method.variant);
+        let call = match &method.argument {
+            ServiceArgument::None => quote! { Ok::<_, flatipc::DecodeError>(self.#name()) },
+            ServiceArgument::Lend(_, ty) => quote! {
+                flatipc::service::lent::<<#ty as flatipc::IntoIpc>::IpcType, _>(&request)
+                    .map(|value| self.#name(value))
+            },
+            ServiceArgument::LendMut(_, ty) => quote! {
+                flatipc::service::lent_mut::<<#ty as flatipc::IntoIpc>::IpcType, _>(&mut request)
+                    .map(|value| self.#name(value))
+            },
+            ServiceArgument::Scalar(_, ty) => quote! {
+                <#ty as flatipc::IpcScalar>::from_scalar_message(&request).map(|value| self.#name(value))
+            },
+        };
+        quote! {
+            Ok(#opcode::#variant) => (#call)
+                .map(flatipc::service::Reply::into_reply)
+                .map_err(flatipc::service::ServiceError::from),
+        }
+    });
+
+    let client_methods = methods.iter().map(|method| {
+        let (name, variant, attrs, output) = (&method.ident, &method.variant, &method.attrs, &method.output);
+        let (arg, send) = match &method.argument {
+            ServiceArgument::None => (
+                quote! {},
+                quote! {
+                    flatipc::Transport::scalar(
+                        &self.transport, self.connection, usize::from(#opcode::#variant), [0; 4])?
+                },
+            ),
+            ServiceArgument::Lend(arg, ty) => (
+                quote! { , #arg: &<#ty as flatipc::IntoIpc>::IpcType },
+                quote! {{
+                    let result = flatipc::Ipc::lend_with(
+                        #arg, &self.transport, self.connection, usize::from(#opcode::#variant))?;
+                    (result.offset, result.valid)
+                }},
+            ),
+            ServiceArgument::LendMut(arg, ty) => (
+                quote! { , #arg: &mut <#ty as flatipc::IntoIpc>::IpcType },
+                quote! {{
+                    let result = flatipc::Ipc::lend_mut_with(
+                        #arg, &self.transport, self.connection, usize::from(#opcode::#variant))?;
+                    (result.offset, result.valid)
+                }},
+            ),
+            ServiceArgument::Scalar(arg, ty) => (
+                quote! { , #arg: #ty },
+                quote! {
+                    flatipc::IpcScalar::blocking_scalar_with(
+                        &#arg, &self.transport, self.connection, usize::from(#opcode::#variant))?
+                },
+            ),
+        };
+        quote! {
+            #(#attrs)*
+            pub fn #name(&self #arg) -> Result<#output, flatipc::service::ServiceError> {
+                let (a, b) = #send;
+                Ok(<#output as flatipc::service::Reply>::from_reply(a, b)?)
+            }
+        }
+    });
+
+    item.items.push(syn::parse_quote! {
+        /// Decode `request`, call the method that corresponds to its opcode, and reply
+        /// with the result. Requests that can't be decoded are replied to with zeroes.
+        fn dispatch<R: flatipc::service::Request>(
+            &mut self,
+            mut request: R,
+        ) -> Result<(), flatipc::service::ServiceError>
+        where
+            Self: Sized,
+        {
+            let result: Result<(usize, usize), flatipc::service::ServiceError> =
+                match #opcode::try_from(request.opcode()) {
+                    #(#dispatch_arms)*
+                    Err(opcode) => Err(flatipc::service::ServiceError::UnknownOpcode(opcode)),
+                };
+            let (a, b) = *result.as_ref().unwrap_or(&(0, 0));
+            request.reply(a, b);
+            result.map(|_| ())
+        }
+    });
+
+    let client_doc = format!("Sends requests to a Server that implements `{}`.", ident);
+    Ok(quote! {
+        #item
+
+        #opcode_enum
+
+        #[doc = #client_doc]
+        #vis struct #client<T: flatipc::Transport = flatipc::DefaultTransport> {
+            transport: T,
+            connection: flatipc::CID,
+        }
+
+        impl #client {
+            /// Send requests to `connection` using the default transport.
+            pub fn new(connection: flatipc::CID) -> Self {
+                Self::with_transport(flatipc::DefaultTransport::default(), connection)
+            }
+        }
+
+        impl<T: flatipc::Transport> #client<T> {
+            /// Send requests to `connection` using `transport`.
+            pub fn with_transport(transport: T, connection: flatipc::CID) -> Self {
+                #client { transport, connection }
+            }
+
+            /// The connection that requests are sent to.
+            pub fn connection(&self) -> flatipc::CID { self.connection }
+
+            #(#client_methods)*
+        }
+    })
+}
+
+/// Turn an enum whose variants each hold one type into a set of messages that may
+/// share an opcode. The enum is replaced with one that borrows the `Ipc` form of each
+/// type, and gains `try_from_slice_mut()`, `from_slice_mut()`, `from_slice_mut_checked()`
+/// and `from_request()`, which select the variant by signature. Only the last two
+/// validate the message, so they should be used for buffers lent by clients.
+#[proc_macro_attribute]
+pub fn message_set(attr: TokenStream, ts: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(proc_macro2::TokenStream::from(attr).span(), "message_set takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(ts as syn::ItemEnum);
+    message_set_inner(item).unwrap_or_else(|e| e).into()
+}
+
+fn message_set_inner(item: syn::ItemEnum) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    if !item.generics.params.is_empty() {
+        return Err(
+            syn::Error::new(item.generics.span(), "message sets may not be generic").to_compile_error()
+        );
+    }
+    let mut variants = vec![];
+    let mut types = vec![];
+    for variant in item.variants.iter() {
+        match &variant.fields {
+            syn::Fields::Unnamed(fields) if fields.unnamed.len() == 1 && variant.discriminant.is_none() => {
+                variants.push(&variant.ident);
+                types.push(&fields.unnamed[0].ty);
+            }
+            _ => {
+                return Err(syn::Error::new(variant.span(), "each message must be a variant holding one type")
+                    .to_compile_error())
+            }
+        }
+    }
+    if types.is_empty() {
+        return Err(
+            syn::Error::new(item.span(), "message sets must have at least one message").to_compile_error()
+        );
+    }
+
+    let (attrs, vis, ident) = (&item.attrs, &item.vis, &item.ident);
+    let variant_attrs = item.variants.iter().map(|variant| &variant.attrs);
+    let ipc_types: Vec<proc_macro2::TokenStream> =
+        types.iter().map(|ty| quote! { <#ty as flatipc::IntoIpc>::IpcType }).collect();
+    let first = &ipc_types[0];
+
+    // The types are matched by signature, so each may only appear once
+    let mut distinct = vec![];
+    for (index, a) in ipc_types.iter().enumerate() {
+        for b in &ipc_types[index + 1..] {
+            distinct.push(quote! {
+                assert!(
+                    <#a as flatipc::Ipc>::SIGNATURE != <#b as flatipc::Ipc>::SIGNATURE,
+                    concat!("`", stringify!(#ident), "` contains the same type more than once")
+                );
+            });
+        }
+    }
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis enum #ident<'a> {
+            #(#(#variant_attrs)* #variants(&'a mut #ipc_types),)*
+        }
+
+        const _: () = {
+            #(#distinct)*
+        };
+
+        impl<'a> #ident<'a> {
+            /// Decode `data` as whichever message has the given signature.
+            pub fn try_from_slice_mut(
+                data: &'a mut [u8],
+                signature: usize,
+            ) -> Result<Self, flatipc::DecodeError> {
+                match signature {
+                    #(<#ipc_types as flatipc::Ipc>::SIGNATURE => Ok(#ident::#variants(
+                        <#ipc_types as flatipc::Ipc>::try_from_slice_mut(data, signature)?,
+                    )),)*
+                    got => Err(flatipc::DecodeError::SignatureMismatch {
+                        expected: <#first as flatipc::Ipc>::SIGNATURE,
+                        got,
+                    }),
+                }
+            }
+
+            /// Decode `data` as whichever message has the given signature, or return
+            /// `None` if it isn't any of them.
+            pub fn from_slice_mut(data: &'a mut [u8], signature: usize) -> Option<Self> {
+                Self::try_from_slice_mut(data, signature).ok()
+            }
+
+            /// Decode `data` as whichever message has the given signature, additionally
+            /// verifying that every field of it holds a valid value.
+            pub fn from_slice_mut_checked(
+                data: &'a mut [u8],
+                signature: usize,
+            ) -> Result<Self, flatipc::DecodeError>
+            where
+                #(for<'__flatipc> <#ipc_types as flatipc::Ipc>::Original: flatipc::Validate,)*
+            {
+                match signature {
+                    #(<#ipc_types as flatipc::Ipc>::SIGNATURE => Ok(#ident::#variants(
+                        <#ipc_types as flatipc::Ipc>::from_slice_mut_checked(data, signature)?,
+                    )),)*
+                    got => Err(flatipc::DecodeError::SignatureMismatch {
+                        expected: <#first as flatipc::Ipc>::SIGNATURE,
+                        got,
+                    }),
+                }
+            }
+
+            /// Decode and validate the buffer that was mutably lent with `request`.
+            pub fn from_request<R: flatipc::service::Request + ?Sized>(
+                request: &'a mut R,
+            ) -> Result<Self, flatipc::DecodeError>
+            where
+                #(for<'__flatipc> <#ipc_types as flatipc::Ipc>::Original: flatipc::Validate,)*
+            {
+                let (data, signature) = request.lent_mut().ok_or(flatipc::DecodeError::NotLent)?;
+                Self::from_slice_mut_checked(data, signature)
+            }
         }
     })
 }
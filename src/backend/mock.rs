@@ -1,52 +1,1000 @@
-use std::sync::{LazyLock, Mutex};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ptr::NonNull;
+use std::sync::mpsc::{SyncSender, sync_channel};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+pub use crate::replay::MessageKind;
+use crate::replay::{Capture, CapturedMessage};
+use crate::{AlignedBuffer, LendResult, Transport};
+
 // Make a CID a u128 just to be different from Xous and ensure
 // the types don't make assumptions.
+#[cfg(not(feature = "xous"))]
 pub type CID = u128;
+#[cfg(feature = "xous")]
+pub use crate::CID;
+
+/// The ways in which the mock machine can fail to deliver a message. These mirror
+/// the equivalent `xous::Error` variants so that error handling can be tested on the host.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// There is no server with the given `CID`, SID or name.
+    ServerNotFound,
+
+    /// A server with the given SID or name already exists.
+    ServerExists,
+
+    /// The server is busy and can't accept the message without blocking.
+    ServerBusy,
+
+    /// The process hosting the server has terminated.
+    ProcessTerminated,
+
+    /// The message is larger than the server is willing to accept.
+    MessageTooLarge,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::ServerNotFound => write!(f, "server not found"),
+            Error::ServerExists => write!(f, "server already exists"),
+            Error::ServerBusy => write!(f, "server is busy"),
+            Error::ProcessTerminated => write!(f, "server process has terminated"),
+            Error::MessageTooLarge => write!(f, "message is too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(feature = "xous")]
+impl From<Error> for ::xous::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::ServerNotFound => ::xous::Error::ServerNotFound,
+            Error::ServerExists => ::xous::Error::ServerExists,
+            Error::ServerBusy => ::xous::Error::ServerQueueFull,
+            Error::ProcessTerminated => ::xous::Error::ProcessTerminated,
+            Error::MessageTooLarge => ::xous::Error::OutOfMemory,
+        }
+    }
+}
+
+type LendFn = Box<dyn Send + Fn(usize, usize, usize, &[u8]) -> (usize, usize)>;
+type LendMutFn = Box<dyn Send + Fn(usize, usize, usize, &mut [u8]) -> (usize, usize)>;
+type ScalarFn = Box<dyn Send + Fn(usize, [usize; 4]) -> (usize, usize)>;
+type MoveFn = Box<dyn Send + Fn(usize, usize, usize, AlignedBuffer)>;
+
+/// The number of messages that may be waiting for a server before further messages
+/// block, or are rejected by `try_` calls. This may be changed with
+/// `ServerHandle::set_queue_depth()` or `Server::with_queue_depth()`.
+pub const DEFAULT_QUEUE_DEPTH: usize = 8;
+
+/// The response that unblocks a client, or the reason it failed.
+type Reply = Result<(usize, usize), Error>;
+
+/// A message received by a server, which mirrors `xous::MessageEnvelope`. The client
+/// stays blocked until the envelope is replied to or dropped. Dropping an envelope
+/// without replying returns `(0, 0)` to the client, as Xous does.
+pub struct Envelope {
+    /// The opcode the client sent the message with.
+    pub opcode: usize,
+
+    /// What sort of message this is.
+    pub kind: MessageKind,
+
+    /// The arguments of a scalar message. For memory messages these are the
+    /// `offset` and `valid` fields, which `flatipc` uses for the signature.
+    pub args: [usize; 4],
+
+    /// The client's buffer. The client is blocked until the envelope is dropped, so this
+    /// remains valid for as long as the envelope exists.
+    buffer: Option<(NonNull<u8>, usize)>,
+
+    /// The buffer of a `Move` message, which now belongs to the server. `buffer` points
+    /// into it until it is taken.
+    moved: Option<AlignedBuffer>,
+    reply: Option<SyncSender<Reply>>,
+}
+
+// The buffer is either owned by the envelope, or by a client that is blocked until
+// the envelope is dropped.
+unsafe impl Send for Envelope {}
+
+impl Envelope {
+    /// The buffer that was lent, if this is a memory message.
+    pub fn buffer(&self) -> Option<&[u8]> {
+        self.buffer.map(|(ptr, len)| unsafe { core::slice::from_raw_parts(ptr.as_ptr() as *const u8, len) })
+    }
+
+    /// The buffer that was lent, if this is a mutable memory message.
+    pub fn buffer_mut(&mut self) -> Option<&mut [u8]> {
+        match self.kind {
+            MessageKind::MutableBorrow | MessageKind::Move => {
+                self.buffer.map(|(ptr, len)| unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), len) })
+            }
+            _ => None,
+        }
+    }
+
+    /// Take ownership of the buffer, if this is a `Move` message. The buffer is
+    /// otherwise released when the envelope is dropped.
+    pub fn take_buffer(&mut self) -> Option<AlignedBuffer> {
+        self.buffer = None;
+        self.moved.take()
+    }
+
+    /// Return the message to the client, unblocking it with the values `a` and `b`. For memory
+    /// messages these become the `offset` and `valid` fields of the `LendResult`.
+    pub fn reply(mut self, a: usize, b: usize) { self.respond(Ok((a, b))); }
+
+    fn respond(&mut self, reply: Reply) {
+        if let Some(sender) = self.reply.take() {
+            sender.send(reply).ok();
+        }
+    }
+}
+
+impl crate::OwnedMessage for Envelope {
+    fn take_buffer(mut self) -> Option<(AlignedBuffer, usize)> {
+        let signature = self.args[0];
+        Envelope::take_buffer(&mut self).map(|buffer| (buffer, signature))
+    }
+}
+
+impl crate::ScalarEnvelope for Envelope {
+    fn scalar_args(&self) -> Option<[usize; 4]> {
+        match self.kind {
+            MessageKind::Scalar | MessageKind::BlockingScalar => Some(self.args),
+            _ => None,
+        }
+    }
+}
+
+impl crate::service::Request for Envelope {
+    fn opcode(&self) -> usize { self.opcode }
+
+    fn lent(&self) -> Option<(&[u8], usize)> {
+        match self.kind {
+            MessageKind::Borrow | MessageKind::MutableBorrow => Some((self.buffer()?, self.args[0])),
+            _ => None,
+        }
+    }
+
+    fn lent_mut(&mut self) -> Option<(&mut [u8], usize)> {
+        let signature = self.args[0];
+        match self.kind {
+            MessageKind::MutableBorrow => Some((self.buffer_mut()?, signature)),
+            _ => None,
+        }
+    }
+
+    fn reply(self, a: usize, b: usize) { Envelope::reply(self, a, b) }
+}
+
+impl Drop for Envelope {
+    fn drop(&mut self) {
+        // A server that panics while holding a message is treated as having terminated
+        if std::thread::panicking() {
+            self.respond(Err(Error::ProcessTerminated));
+        } else {
+            self.respond(Ok((0, 0)));
+        }
+    }
+}
+
+/// Whether a server is still able to receive messages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Status {
+    Running,
+    Destroyed,
+    Terminated,
+}
 
+struct QueueState {
+    envelopes: VecDeque<Envelope>,
+    depth: usize,
+    max_message_size: Option<usize>,
+    status: Status,
+}
+
+/// The messages waiting for a server. This is shared between the server's
+/// `ServerHandle` and every client that is connected to it.
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Queue {
+            state: Mutex::new(QueueState {
+                envelopes: VecDeque::new(),
+                depth: DEFAULT_QUEUE_DEPTH,
+                max_message_size: None,
+                status: Status::Running,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn check(status: Status) -> Result<(), Error> {
+        match status {
+            Status::Running => Ok(()),
+            Status::Destroyed => Err(Error::ServerNotFound),
+            Status::Terminated => Err(Error::ProcessTerminated),
+        }
+    }
+
+    /// Stop the server from receiving messages, failing every message that is waiting.
+    fn close(&self, status: Status) {
+        let mut state = self.state.lock().unwrap();
+        if state.status != Status::Running {
+            return;
+        }
+        state.status = status;
+        let error = Queue::check(status).unwrap_err();
+        for mut envelope in state.envelopes.drain(..) {
+            envelope.respond(Err(error));
+        }
+        self.changed.notify_all();
+    }
+
+    /// Add an envelope to the queue. If the queue is full, either wait for space or fail
+    /// with `Error::ServerBusy`, depending on `block`.
+    fn post(&self, envelope: Envelope, block: bool) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            Queue::check(state.status)?;
+            if state.envelopes.len() < state.depth {
+                state.envelopes.push_back(envelope);
+                self.changed.notify_all();
+                return Ok(());
+            }
+            if !block {
+                return Err(Error::ServerBusy);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn receive(&self, block: bool) -> Result<Option<Envelope>, Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            Queue::check(state.status)?;
+            if let Some(envelope) = state.envelopes.pop_front() {
+                self.changed.notify_all();
+                return Ok(Some(envelope));
+            }
+            if !block {
+                return Ok(None);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Send a message and, unless it is a non-blocking scalar, wait for the server to respond.
+    fn call(
+        &self,
+        opcode: usize,
+        kind: MessageKind,
+        args: [usize; 4],
+        buffer: Option<&mut AlignedBuffer>,
+        block: bool,
+    ) -> Result<(usize, usize), Error> {
+        if let Some(buffer) = &buffer {
+            self.check_size(buffer)?;
+        }
+        let buffer = buffer.map(|buffer| (NonNull::new(buffer.as_mut_ptr()).unwrap(), buffer.len()));
+        if kind == MessageKind::Scalar {
+            self.post(Envelope { opcode, kind, args, buffer, moved: None, reply: None }, block)?;
+            return Ok((0, 0));
+        }
+        let (sender, receiver) = sync_channel(1);
+        self.post(Envelope { opcode, kind, args, buffer, moved: None, reply: Some(sender) }, block)?;
+        // Every envelope replies when it's dropped, so the sender can't disappear silently
+        receiver.recv().unwrap_or(Err(Error::ProcessTerminated))
+    }
+
+    /// Hand `buffer` over to the server without waiting for it to be received.
+    fn move_buffer(&self, opcode: usize, args: [usize; 4], mut buffer: AlignedBuffer) -> Result<(), Error> {
+        self.check_size(&buffer)?;
+        let pointer = Some((NonNull::new(buffer.as_mut_ptr()).unwrap(), buffer.len()));
+        let kind = MessageKind::Move;
+        self.post(Envelope { opcode, kind, args, buffer: pointer, moved: Some(buffer), reply: None }, true)
+    }
+
+    fn check_size(&self, buffer: &AlignedBuffer) -> Result<(), Error> {
+        let max_message_size = self.state.lock().unwrap().max_message_size;
+        if max_message_size.is_some_and(|max| buffer.len() > max) {
+            return Err(Error::MessageTooLarge);
+        }
+        Ok(())
+    }
+}
+
+/// The receiving end of a mock server. Messages sent to the server wait in a queue
+/// until they are received.
+///
+/// Dropping the handle terminates the server, failing any messages that are waiting
+/// and any that are sent later with `Error::ProcessTerminated`.
+pub struct ServerHandle {
+    sid: SID,
+    queue: Arc<Queue>,
+    machine: Weak<Mutex<IpcMachine>>,
+}
+
+impl ServerHandle {
+    /// The SID that clients may `connect()` to.
+    pub fn sid(&self) -> SID { self.sid }
+
+    /// Wait for the next message. Fails once the server has been destroyed or terminated.
+    pub fn receive(&self) -> Result<Envelope, Error> { Ok(self.queue.receive(true)?.unwrap()) }
+
+    /// Return the next message if one is waiting.
+    pub fn try_receive(&self) -> Result<Option<Envelope>, Error> { self.queue.receive(false) }
+
+    /// Return the number of messages waiting for the server.
+    pub fn queued(&self) -> usize { self.queue.state.lock().unwrap().envelopes.len() }
+
+    /// Set the number of messages that may wait for the server. Once the queue is full,
+    /// blocking calls wait for space and `try_` calls fail with `Error::ServerBusy`.
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue.state.lock().unwrap().depth = depth;
+        self.queue.changed.notify_all();
+    }
+
+    /// Limit the size of the buffers that may be lent to the server. Larger buffers
+    /// are rejected with `Error::MessageTooLarge`.
+    pub fn set_max_message_size(&self, max_message_size: Option<usize>) {
+        self.queue.state.lock().unwrap().max_message_size = max_message_size;
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) { self.queue.close(Status::Terminated) }
+}
+
+/// A server made up of closures, which is run on its own thread by `Server::spawn()`.
 pub struct Server {
-    lend: Box<dyn Send + Fn(usize, usize, usize, &[u8]) -> (usize, usize)>,
-    lend_mut: Box<dyn Send + Fn(usize, usize, usize, &mut [u8]) -> (usize, usize)>,
+    lend: LendFn,
+    lend_mut: LendMutFn,
+    scalar: Option<ScalarFn>,
+    moved: Option<MoveFn>,
+    queue_depth: usize,
+    max_message_size: Option<usize>,
 }
 
 impl Server {
-    pub fn new(
-        lend: Box<dyn Send + Fn(usize, usize, usize, &[u8]) -> (usize, usize)>,
-        lend_mut: Box<dyn Send + Fn(usize, usize, usize, &mut [u8]) -> (usize, usize)>,
-    ) -> Self {
-        Server { lend, lend_mut }
+    pub fn new(lend: LendFn, lend_mut: LendMutFn) -> Self {
+        Server {
+            lend,
+            lend_mut,
+            scalar: None,
+            moved: None,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            max_message_size: None,
+        }
+    }
+
+    /// Attach a handler for scalar messages. The handler receives the opcode and the
+    /// four arguments, and its return value is passed back to blocking callers.
+    pub fn with_scalar(mut self, scalar: ScalarFn) -> Self {
+        self.scalar = Some(scalar);
+        self
+    }
+
+    /// Attach a handler for buffers that are moved to the server. The handler receives
+    /// the opcode, the signature, the second argument and the buffer, which it owns.
+    pub fn with_move(mut self, moved: MoveFn) -> Self {
+        self.moved = Some(moved);
+        self
+    }
+
+    /// Set the number of messages that may wait for the server.
+    pub fn with_queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = queue_depth;
+        self
     }
+
+    /// Limit the size of the buffers that may be lent to the server. Larger buffers
+    /// are rejected with `Error::MessageTooLarge`.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /// Run the server on a new thread, receiving messages from `handle` until the
+    /// server is destroyed or terminated.
+    pub fn spawn(self, handle: ServerHandle) -> std::thread::JoinHandle<()> {
+        handle.set_queue_depth(self.queue_depth);
+        handle.set_max_message_size(self.max_message_size);
+        std::thread::spawn(move || {
+            // Messages sent by the server using `Mock` go to the machine the server belongs to
+            let _machine = MachineGuard::enter(handle.machine.clone());
+            while let Ok(mut envelope) = handle.receive() {
+                let opcode = envelope.opcode;
+                let [a, b, ..] = envelope.args;
+                let (a, b) = match envelope.kind {
+                    MessageKind::Borrow => (self.lend)(opcode, a, b, envelope.buffer().unwrap()),
+                    MessageKind::MutableBorrow => {
+                        (self.lend_mut)(opcode, a, b, envelope.buffer_mut().unwrap())
+                    }
+                    MessageKind::Scalar | MessageKind::BlockingScalar => match &self.scalar {
+                        Some(scalar) => scalar(opcode, envelope.args),
+                        None => (0, 0),
+                    },
+                    MessageKind::Move => {
+                        if let (Some(moved), Some(buffer)) = (&self.moved, envelope.take_buffer()) {
+                            moved(opcode, a, b, buffer);
+                        }
+                        (0, 0)
+                    }
+                };
+                envelope.reply(a, b);
+            }
+        })
+    }
+}
+
+/// The largest alignment of the client's buffer that is preserved in the copy
+/// given to the server, which allows for types with a `page_size` of up to 64 KiB.
+const MAX_PRESERVED_ALIGNMENT: usize = 65536;
+
+/// Copy `data` into a buffer that is page-aligned, and that is at least as aligned
+/// as `data` so that types with a larger `page_size` can still be received.
+fn copy_to_server(data: &[u8]) -> AlignedBuffer {
+    let align = 1usize << (data.as_ptr() as usize).trailing_zeros().min(MAX_PRESERVED_ALIGNMENT.ilog2());
+    let mut buffer = AlignedBuffer::with_alignment(data.len(), align);
+    buffer.copy_from_slice(data);
+    buffer
 }
 
+/// A 16-byte server identifier, as passed to `xous::create_server_with_sid()`.
+pub type SID = [u8; 16];
+
+/// A message sent through a `MockMachine` while it was recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// How long after recording started the message was sent.
+    pub time: Duration,
+
+    /// The connection the message was sent to.
+    pub connection: CID,
+
+    /// The opcode the message was sent with.
+    pub opcode: usize,
+
+    /// What sort of message this was.
+    pub kind: MessageKind,
+
+    /// The arguments of the message. For lent buffers, the first argument is the
+    /// signature and the remainder are zero.
+    pub args: [usize; 4],
+
+    /// The lent buffer as the server received it, or `None` for scalar messages.
+    pub before: Option<Vec<u8>>,
+
+    /// The lent buffer as it was returned to the client, or `None` for scalar messages.
+    pub after: Option<Vec<u8>>,
+
+    /// What the server responded with, or why the message could not be delivered.
+    pub result: Result<(usize, usize), Error>,
+}
+
+impl Record {
+    /// The signature of a lent buffer, or `None` for scalar messages.
+    pub fn signature(&self) -> Option<usize> { self.before.as_ref().map(|_| self.args[0]) }
+}
+
+impl From<Record> for CapturedMessage {
+    fn from(record: Record) -> Self {
+        CapturedMessage {
+            time: record.time,
+            connection: record.connection,
+            opcode: record.opcode,
+            kind: record.kind,
+            args: record.args,
+            payload: record.before,
+            response: record.after,
+            result: record.result.ok(),
+        }
+    }
+}
+
+/// Collects a `Record` of every message sent through a `MockMachine`. Recorders are
+/// shared, so records may be inspected while the machine is still running.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    records: Arc<Mutex<Vec<Record>>>,
+    start: Instant,
+}
+
+impl Recorder {
+    fn new() -> Self { Recorder { records: Arc::default(), start: Instant::now() } }
+
+    /// A copy of every message recorded so far, in the order the messages were sent.
+    pub fn records(&self) -> Vec<Record> { self.records.lock().unwrap().clone() }
+
+    /// Remove and return every message recorded so far.
+    pub fn take(&self) -> Vec<Record> { core::mem::take(&mut *self.records.lock().unwrap()) }
+
+    /// Every message recorded so far as a `Capture`, which may be saved to a file
+    /// and replayed against a server later.
+    pub fn capture(&self) -> Capture {
+        Capture { messages: self.records().into_iter().map(Into::into).collect() }
+    }
+
+    fn push(&self, record: Record) { self.records.lock().unwrap().push(record) }
+}
+
+/// The registry of servers and connections of a `MockMachine`. Its lock is only
+/// held while looking up a connection, never while a message is being delivered,
+/// so servers may freely send messages to other servers.
 pub struct IpcMachine {
-    servers: Vec<Server>,
+    /// The server each `CID` is connected to. Connections that have been closed,
+    /// or whose server was destroyed, are `None`.
+    connections: Vec<Option<Arc<Queue>>>,
+
+    /// Every server that hasn't been destroyed.
+    sids: HashMap<SID, Arc<Queue>>,
+
+    /// The SID of every server that was registered with a name.
+    names: HashMap<String, SID>,
+
+    /// Used to assign SIDs to servers that weren't given one.
+    next_sid: u64,
+
+    /// The `MockMachine` this is contained in, which servers send messages through.
+    this: Weak<Mutex<IpcMachine>>,
+
+    /// Where messages are recorded, if recording has been started.
+    recorder: Option<Recorder>,
 }
 
-pub(crate) static IPC_MACHINE: LazyLock<Mutex<IpcMachine>> = LazyLock::new(|| Mutex::new(IpcMachine::new()));
+/// The machine used by `Mock` on threads that haven't entered a machine of their own.
+pub static IPC_MACHINE: LazyLock<MockMachine> = LazyLock::new(MockMachine::new);
 
 impl IpcMachine {
-    fn new() -> Self { IpcMachine { servers: Vec::new() } }
+    fn new(this: Weak<Mutex<IpcMachine>>) -> Self {
+        IpcMachine {
+            connections: Vec::new(),
+            sids: HashMap::new(),
+            names: HashMap::new(),
+            next_sid: 0,
+            this,
+            recorder: None,
+        }
+    }
+
+    /// Record every message sent from now on, including messages that servers send
+    /// to one another and messages that fail. Any previous recorder is replaced.
+    pub fn start_recording(&mut self) -> Recorder {
+        let recorder = Recorder::new();
+        self.recorder = Some(recorder.clone());
+        recorder
+    }
+
+    /// Stop recording messages. Records that were already made are kept by the `Recorder`.
+    pub fn stop_recording(&mut self) { self.recorder = None; }
 
+    /// Run `server` on its own thread and return a connection to it.
     pub fn add_server(&mut self, server: Server) -> CID {
-        let server_id = self.servers.len() as CID;
-        self.servers.push(server);
-        server_id
+        let handle = self.create_server();
+        let connection = self.open_connection(handle.queue.clone());
+        server.spawn(handle);
+        connection
+    }
+
+    /// Create a server with a newly-assigned SID.
+    pub fn create_server(&mut self) -> ServerHandle {
+        loop {
+            let mut sid = *b"mock-sid\0\0\0\0\0\0\0\0";
+            sid[8..].copy_from_slice(&self.next_sid.to_le_bytes());
+            self.next_sid += 1;
+            if let Ok(handle) = self.create_server_with_sid(sid) {
+                return handle;
+            }
+        }
+    }
+
+    /// Create a server that clients may `connect()` to using `sid`, failing with
+    /// `Error::ServerExists` if `sid` is already in use.
+    pub fn create_server_with_sid(&mut self, sid: SID) -> Result<ServerHandle, Error> {
+        if self.sids.contains_key(&sid) {
+            return Err(Error::ServerExists);
+        }
+        let queue = Arc::new(Queue::new());
+        self.sids.insert(sid, queue.clone());
+        Ok(ServerHandle { sid, queue, machine: self.this.clone() })
+    }
+
+    /// Create a server that clients may find using `connect_by_name()`, in the same way
+    /// as registering with `xous-names`.
+    pub fn create_server_with_name(&mut self, name: &str) -> Result<ServerHandle, Error> {
+        if self.names.contains_key(name) {
+            return Err(Error::ServerExists);
+        }
+        let handle = self.create_server();
+        self.names.insert(name.into(), handle.sid());
+        Ok(handle)
+    }
+
+    /// Open a new connection to the server with the given `sid`.
+    pub fn connect(&mut self, sid: SID) -> Result<CID, Error> {
+        let queue = self.sids.get(&sid).ok_or(Error::ServerNotFound)?.clone();
+        Ok(self.open_connection(queue))
+    }
+
+    /// Open a new connection to the server registered as `name`.
+    pub fn connect_by_name(&mut self, name: &str) -> Result<CID, Error> {
+        let sid = *self.names.get(name).ok_or(Error::ServerNotFound)?;
+        self.connect(sid)
+    }
+
+    /// Close a connection. Further messages sent using `server_id` fail with
+    /// `Error::ServerNotFound`.
+    pub fn disconnect(&mut self, server_id: CID) -> Result<(), Error> {
+        let connection = usize::try_from(server_id).ok().and_then(|index| self.connections.get_mut(index));
+        match connection {
+            Some(connection @ Some(_)) => {
+                *connection = None;
+                Ok(())
+            }
+            _ => Err(Error::ServerNotFound),
+        }
+    }
+
+    /// Destroy the server with the given `sid`. Its SID and name may be reused, and
+    /// messages sent over existing connections fail with `Error::ServerNotFound`.
+    pub fn destroy_server(&mut self, sid: SID) -> Result<(), Error> {
+        let queue = self.sids.remove(&sid).ok_or(Error::ServerNotFound)?;
+        self.names.retain(|_, named| *named != sid);
+        queue.close(Status::Destroyed);
+        for connection in self.connections.iter_mut() {
+            if connection.as_ref().is_some_and(|connected| Arc::ptr_eq(connected, &queue)) {
+                *connection = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Terminate the process hosting the server. Any further messages to the server
+    /// fail with `Error::ProcessTerminated`.
+    pub fn terminate(&mut self, server_id: CID) -> Result<(), Error> {
+        self.queue(server_id)?.close(Status::Terminated);
+        Ok(())
+    }
+
+    fn open_connection(&mut self, queue: Arc<Queue>) -> CID {
+        self.connections.push(Some(queue));
+        (self.connections.len() - 1) as CID
+    }
+
+    fn queue(&self, server_id: CID) -> Result<Arc<Queue>, Error> {
+        let connection = usize::try_from(server_id).ok().and_then(|index| self.connections.get(index));
+        let Some(Some(queue)) = connection else { return Err(Error::ServerNotFound) };
+        Ok(queue.clone())
+    }
+}
+
+impl Drop for IpcMachine {
+    // Wake up every server so that their threads can exit
+    fn drop(&mut self) {
+        for queue in self.sids.values() {
+            queue.close(Status::Destroyed);
+        }
+    }
+}
+
+thread_local! {
+    /// The machine that `Mock` uses on this thread, if one has been entered.
+    static CURRENT_MACHINE: RefCell<Option<Weak<Mutex<IpcMachine>>>> = const { RefCell::new(None) };
+}
+
+/// An independent set of servers and connections. Each test may create its own
+/// `MockMachine` so that servers and `CID`s aren't shared with other tests running
+/// in parallel. `MockMachine` derefs to the `Mutex` around its `IpcMachine`, and is
+/// itself a `Transport` that delivers messages to its servers:
+///
+/// ```ignore
+/// let machine = MockMachine::new();
+/// let connection = machine.lock().unwrap().add_server(server);
+/// value.lend_with(&machine, connection, 0)?;
+/// ```
+///
+/// Code that uses `Mock`, including the default transport of generated `lend()`
+/// methods without the `xous` feature, can be pointed at a machine with `enter()`.
+#[derive(Clone)]
+pub struct MockMachine(Arc<Mutex<IpcMachine>>);
+
+impl MockMachine {
+    pub fn new() -> Self { MockMachine(Arc::new_cyclic(|this| Mutex::new(IpcMachine::new(this.clone())))) }
+
+    /// Make this the machine that `Mock` delivers messages to on the current thread,
+    /// until the returned guard is dropped. Servers added to this machine always
+    /// use it for the messages they send.
+    pub fn enter(&self) -> MachineGuard { MachineGuard::enter(Arc::downgrade(&self.0)) }
+
+    /// The machine that `Mock` delivers messages to on the current thread. This is
+    /// `IPC_MACHINE` unless another machine has been entered.
+    pub fn current() -> Result<MockMachine, Error> {
+        match CURRENT_MACHINE.with(|current| current.borrow().clone()) {
+            Some(machine) => machine.upgrade().map(MockMachine).ok_or(Error::ServerNotFound),
+            None => Ok(IPC_MACHINE.clone()),
+        }
+    }
+
+    /// Lend `buffer` to the server and wait for it to be returned. If the server's queue
+    /// is full, either wait for space or fail with `Error::ServerBusy`, depending on `block`.
+    fn lend_buffer(
+        &self,
+        connection: CID,
+        opcode: usize,
+        kind: MessageKind,
+        signature: usize,
+        buffer: &mut AlignedBuffer,
+        block: bool,
+    ) -> Result<LendResult, Error> {
+        let (offset, valid) =
+            self.deliver(connection, opcode, kind, [signature, 0, 0, 0], Some(buffer), block)?;
+        Ok(LendResult { offset, valid })
+    }
+
+    fn scalar_message(
+        &self,
+        connection: CID,
+        opcode: usize,
+        kind: MessageKind,
+        args: [usize; 4],
+    ) -> Result<(usize, usize), Error> {
+        // As with the kernel, `send` doesn't wait for a reply but does wait for space in the queue
+        self.deliver(connection, opcode, kind, args, None, true)
+    }
+
+    /// Send a message to the server and wait for its response, recording it if the
+    /// machine is recording.
+    fn deliver(
+        &self,
+        connection: CID,
+        opcode: usize,
+        kind: MessageKind,
+        args: [usize; 4],
+        mut buffer: Option<&mut AlignedBuffer>,
+        block: bool,
+    ) -> Result<(usize, usize), Error> {
+        let (queue, recorder) = self.lookup(connection);
+        let Some(recorder) = recorder else {
+            return queue?.call(opcode, kind, args, buffer, block);
+        };
+
+        let time = recorder.start.elapsed();
+        let before = buffer.as_deref().map(|buffer| buffer.to_vec());
+        let result = queue.and_then(|queue| queue.call(opcode, kind, args, buffer.as_deref_mut(), block));
+        let after = buffer.map(|buffer| buffer.to_vec());
+        recorder.push(Record { time, connection, opcode, kind, args, before, after, result });
+        result
+    }
+
+    /// Move `buffer` to the server, recording it if the machine is recording. The
+    /// client keeps no copy of a moved buffer, so there is nothing to record afterwards.
+    fn move_buffer(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        buffer: AlignedBuffer,
+    ) -> Result<(), Error> {
+        let (queue, recorder) = self.lookup(connection);
+        let args = [signature, 0, 0, 0];
+        let Some(recorder) = recorder else {
+            return queue?.move_buffer(opcode, args, buffer);
+        };
+
+        let time = recorder.start.elapsed();
+        let before = Some(buffer.to_vec());
+        let result = queue.and_then(|queue| queue.move_buffer(opcode, args, buffer));
+        let kind = MessageKind::Move;
+        recorder.push(Record {
+            time,
+            connection,
+            opcode,
+            kind,
+            args,
+            before,
+            after: None,
+            result: result.map(|()| (0, 0)),
+        });
+        result
+    }
+
+    /// Find the server that `connection` leads to, along with the current recorder.
+    fn lookup(&self, connection: CID) -> (Result<Arc<Queue>, Error>, Option<Recorder>) {
+        let machine = self.lock().unwrap();
+        (machine.queue(connection), machine.recorder.clone())
+    }
+}
+
+impl Default for MockMachine {
+    fn default() -> Self { Self::new() }
+}
+
+impl core::ops::Deref for MockMachine {
+    type Target = Mutex<IpcMachine>;
+
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+/// Restores the previous machine of the current thread when dropped.
+pub struct MachineGuard {
+    previous: Option<Weak<Mutex<IpcMachine>>>,
+}
+
+impl MachineGuard {
+    fn enter(machine: Weak<Mutex<IpcMachine>>) -> Self {
+        let previous = CURRENT_MACHINE.with(|current| current.replace(Some(machine)));
+        MachineGuard { previous }
+    }
+}
+
+impl Drop for MachineGuard {
+    fn drop(&mut self) { CURRENT_MACHINE.with(|current| *current.borrow_mut() = self.previous.take()) }
+}
+
+/// A `Transport` that delivers messages to the current `MockMachine`, which is
+/// `IPC_MACHINE` unless another machine has been entered. Each message is placed in
+/// the server's queue, and calls that expect a response block until the server replies.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Mock;
+
+// As with the kernel remapping pages into another process, servers receive a
+// page-aligned copy of the data regardless of the alignment of the original.
+impl Transport for MockMachine {
+    fn lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, crate::Error> {
+        let mut buffer = copy_to_server(data);
+        let result =
+            self.lend_buffer(connection, opcode, MessageKind::Borrow, signature, &mut buffer, true)?;
+        Ok(result)
+    }
+
+    fn try_lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, crate::Error> {
+        let mut buffer = copy_to_server(data);
+        let result =
+            self.lend_buffer(connection, opcode, MessageKind::Borrow, signature, &mut buffer, false)?;
+        Ok(result)
+    }
+
+    fn lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, crate::Error> {
+        let mut buffer = copy_to_server(data);
+        let result =
+            self.lend_buffer(connection, opcode, MessageKind::MutableBorrow, signature, &mut buffer, true)?;
+        data.copy_from_slice(&buffer);
+        Ok(result)
+    }
+
+    fn try_lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, crate::Error> {
+        let mut buffer = copy_to_server(data);
+        let result =
+            self.lend_buffer(connection, opcode, MessageKind::MutableBorrow, signature, &mut buffer, false)?;
+        data.copy_from_slice(&buffer);
+        Ok(result)
+    }
+
+    fn send(&self, connection: CID, opcode: usize, args: [usize; 4]) -> Result<(), crate::Error> {
+        self.scalar_message(connection, opcode, MessageKind::Scalar, args)?;
+        Ok(())
+    }
+
+    fn scalar(
+        &self,
+        connection: CID,
+        opcode: usize,
+        args: [usize; 4],
+    ) -> Result<(usize, usize), crate::Error> {
+        let result = self.scalar_message(connection, opcode, MessageKind::BlockingScalar, args)?;
+        Ok(result)
+    }
+
+    fn move_memory(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: AlignedBuffer,
+    ) -> Result<(), crate::Error> {
+        self.move_buffer(connection, opcode, signature, data)?;
+        Ok(())
+    }
+}
+
+impl Transport for Mock {
+    fn lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, crate::Error> {
+        MockMachine::current()?.lend(connection, opcode, signature, data)
+    }
+
+    fn try_lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, crate::Error> {
+        MockMachine::current()?.try_lend(connection, opcode, signature, data)
+    }
+
+    fn lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, crate::Error> {
+        MockMachine::current()?.lend_mut(connection, opcode, signature, data)
     }
 
-    pub fn lend(&self, server_id: CID, opcode: usize, a: usize, b: usize, data: &[u8]) {
-        let server_id = server_id as usize;
-        (self.servers[server_id].lend)(opcode, a, b, data);
+    fn try_lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, crate::Error> {
+        MockMachine::current()?.try_lend_mut(connection, opcode, signature, data)
     }
 
-    pub fn lend_mut(&self, server_id: CID, opcode: usize, a: usize, b: usize, data: &mut [u8]) {
-        let server_id = server_id as usize;
-        (self.servers[server_id].lend_mut)(opcode, a, b, data);
+    fn send(&self, connection: CID, opcode: usize, args: [usize; 4]) -> Result<(), crate::Error> {
+        MockMachine::current()?.send(connection, opcode, args)
     }
 
-    pub fn try_lend(&self, server_id: CID, opcode: usize, a: usize, b: usize, data: &[u8]) {
-        self.lend(server_id, opcode, a, b, data);
+    fn scalar(
+        &self,
+        connection: CID,
+        opcode: usize,
+        args: [usize; 4],
+    ) -> Result<(usize, usize), crate::Error> {
+        MockMachine::current()?.scalar(connection, opcode, args)
     }
 
-    fn try_lend_mut(&self, server_id: CID, opcode: usize, a: usize, b: usize, data: &mut [u8]) {
-        self.lend_mut(server_id, opcode, a, b, data);
+    fn move_memory(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: AlignedBuffer,
+    ) -> Result<(), crate::Error> {
+        MockMachine::current()?.move_memory(connection, opcode, signature, data)
     }
 }
@@ -0,0 +1,190 @@
+use core::ptr::NonNull;
+
+use ::xous::definitions::{MemoryAddress, MemoryMessage, MemoryRange, MemorySize};
+
+use crate::{AlignedBuffer, CID, Error, LendResult, OwnedMessage, PAGE_SIZE, ScalarEnvelope, Transport};
+
+/// A `Transport` that sends messages to other processes using the Xous kernel.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Xous;
+
+fn lend_result(result: ::xous::Result) -> Result<LendResult, Error> {
+    match result {
+        ::xous::Result::MemoryReturned(offset, valid) => Ok(LendResult {
+            offset: offset.map(|offset| offset.get()).unwrap_or_default(),
+            valid: valid.map(|valid| valid.get()).unwrap_or_default(),
+        }),
+        _ => Err(Error::InternalError),
+    }
+}
+
+fn memory_message(
+    opcode: usize,
+    signature: usize,
+    data: *const u8,
+    len: usize,
+) -> Result<MemoryMessage, Error> {
+    let buf = unsafe { MemoryRange::new(data as usize, len) }?;
+    Ok(MemoryMessage { id: opcode, buf, offset: MemoryAddress::new(signature), valid: None })
+}
+
+impl Transport for Xous {
+    fn lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, Error> {
+        let msg = memory_message(opcode, signature, data.as_ptr(), data.len())?;
+        lend_result(::xous::send_message(connection, ::xous::Message::Borrow(msg))?)
+    }
+
+    fn try_lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, Error> {
+        let msg = memory_message(opcode, signature, data.as_ptr(), data.len())?;
+        lend_result(::xous::try_send_message(connection, ::xous::Message::Borrow(msg))?)
+    }
+
+    fn lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, Error> {
+        let msg = memory_message(opcode, signature, data.as_ptr(), data.len())?;
+        lend_result(::xous::send_message(connection, ::xous::Message::MutableBorrow(msg))?)
+    }
+
+    fn try_lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, Error> {
+        let msg = memory_message(opcode, signature, data.as_ptr(), data.len())?;
+        lend_result(::xous::try_send_message(connection, ::xous::Message::MutableBorrow(msg))?)
+    }
+
+    fn send(&self, connection: CID, opcode: usize, args: [usize; 4]) -> Result<(), Error> {
+        let [a1, a2, a3, a4] = args;
+        ::xous::send_message(connection, ::xous::Message::new_scalar(opcode, a1, a2, a3, a4))?;
+        Ok(())
+    }
+
+    fn scalar(&self, connection: CID, opcode: usize, args: [usize; 4]) -> Result<(usize, usize), Error> {
+        let [a1, a2, a3, a4] = args;
+        let msg = ::xous::Message::new_blocking_scalar(opcode, a1, a2, a3, a4);
+        match ::xous::send_message(connection, msg)? {
+            ::xous::Result::Scalar1(a) => Ok((a, 0)),
+            ::xous::Result::Scalar2(a, b) => Ok((a, b)),
+            ::xous::Result::Scalar5(a, b, _, _, _) => Ok((a, b)),
+            _ => Err(Error::InternalError),
+        }
+    }
+
+    fn move_memory(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: AlignedBuffer,
+    ) -> Result<(), Error> {
+        // Buffers with a larger alignment come from the heap rather than from mapped
+        // pages, and can't be handed to another process
+        let data = if data.align() > PAGE_SIZE { AlignedBuffer::copy_from(&data) } else { data };
+        let msg = memory_message(opcode, signature, data.as_ptr(), data.capacity())?;
+        match ::xous::send_message(connection, ::xous::Message::Move(msg)) {
+            // The pages have been unmapped from this process and now belong to the server
+            #[cfg(target_os = "xous")]
+            Ok(_) => core::mem::forget(data),
+            // Hosted builds send a copy of the buffer, so the heap allocation is still ours
+            #[cfg(not(target_os = "xous"))]
+            Ok(_) => drop(data),
+            // The kernel refused the message, so the pages were never unmapped
+            Err(e) => {
+                drop(data);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OwnedMessage for ::xous::MessageEnvelope {
+    fn take_buffer(self) -> Option<(AlignedBuffer, usize)> {
+        if !matches!(self.body, ::xous::Message::Move(_)) {
+            return None;
+        }
+        // The envelope would otherwise unmap the pages when it is dropped
+        let ::xous::Message::Move(msg) = self.take_message() else { unreachable!() };
+        let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
+        let ptr = NonNull::new(msg.buf.as_mut_ptr())?;
+        Some((unsafe { AlignedBuffer::from_raw_parts(ptr, msg.buf.len(), PAGE_SIZE) }, signature))
+    }
+}
+
+impl ScalarEnvelope for ::xous::ScalarMessage {
+    fn scalar_args(&self) -> Option<[usize; 4]> { Some([self.arg1, self.arg2, self.arg3, self.arg4]) }
+}
+
+impl ScalarEnvelope for ::xous::Message {
+    fn scalar_args(&self) -> Option<[usize; 4]> {
+        match self {
+            ::xous::Message::Scalar(msg) | ::xous::Message::BlockingScalar(msg) => msg.scalar_args(),
+            _ => None,
+        }
+    }
+}
+
+impl ScalarEnvelope for ::xous::MessageEnvelope {
+    fn scalar_args(&self) -> Option<[usize; 4]> { self.body.scalar_args() }
+}
+
+impl crate::service::Request for ::xous::MessageEnvelope {
+    fn opcode(&self) -> usize { self.body.id() }
+
+    fn lent(&self) -> Option<(&[u8], usize)> {
+        match &self.body {
+            ::xous::Message::Borrow(msg) | ::xous::Message::MutableBorrow(msg) => {
+                let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
+                Some((unsafe { core::slice::from_raw_parts(msg.buf.as_ptr(), msg.buf.len()) }, signature))
+            }
+            _ => None,
+        }
+    }
+
+    fn lent_mut(&mut self) -> Option<(&mut [u8], usize)> {
+        match &mut self.body {
+            ::xous::Message::MutableBorrow(msg) => {
+                let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
+                Some((
+                    unsafe { core::slice::from_raw_parts_mut(msg.buf.as_mut_ptr(), msg.buf.len()) },
+                    signature,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn reply(mut self, a: usize, b: usize) {
+        match &mut self.body {
+            // Lent memory is returned along with `offset` and `valid` when the envelope is dropped
+            ::xous::Message::Borrow(msg) | ::xous::Message::MutableBorrow(msg) => {
+                msg.offset = MemoryAddress::new(a);
+                msg.valid = MemorySize::new(b);
+            }
+            ::xous::Message::BlockingScalar(_) => {
+                ::xous::return_scalar2(self.sender, a, b).ok();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+
+use crate::{AlignedBuffer, CID, DecodeError, DefaultTransport, Ipc, Transport, backend};
+
+/// An `Ipc` object that lives in its own page-aligned heap allocation.
+///
+/// `Ipc` objects are at least one page long, and returning them by value from
+/// `into_ipc()` places a page-aligned copy on the stack. This is enough to
+/// overflow the small stacks of Xous threads, particularly for messages that
+/// span several pages. `IntoIpc::into_ipc_boxed()` instead writes the original
+/// object directly into freshly-mapped memory.
+///
+/// `IpcBox` dereferences to the `Ipc` object, so it may be lent in the same way:
+///
+/// ```ignore
+/// let mut view = TextView::default().into_ipc_boxed();
+/// view.lend_mut(conn, 42)?;
+/// ```
+pub struct IpcBox<T: Ipc> {
+    buffer: AlignedBuffer,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Ipc> IpcBox<T> {
+    /// Move `value` onto the heap. Since `value` is passed by value, this still
+    /// requires `T` to fit on the stack. Prefer `IntoIpc::into_ipc_boxed()`.
+    pub fn new(value: T) -> Self {
+        let mut boxed = unsafe { Self::zeroed() };
+        unsafe { boxed.as_mut_ptr().write(value) };
+        // Generic `Ipc` types have no padding field, so moving `value` leaves the
+        // bytes after the original object uninitialised.
+        let (start, size) = (core::mem::size_of::<T::Original>(), core::mem::size_of::<T>());
+        unsafe { (boxed.as_mut_ptr() as *mut u8).add(start).write_bytes(0, size - start) };
+        boxed
+    }
+
+    /// Allocate a box whose contents are entirely zero.
+    ///
+    /// # Safety
+    ///
+    /// All-zeroes may not be a valid `T`. The caller must initialize the contents
+    /// through `as_mut_ptr()` before the box is dereferenced or dropped.
+    pub unsafe fn zeroed() -> Self {
+        let buffer = AlignedBuffer::with_alignment(core::mem::size_of::<T>(), core::mem::align_of::<T>());
+        IpcBox { buffer, _marker: PhantomData }
+    }
+
+    /// Take ownership of a buffer that holds a `T`, such as one that was moved to
+    /// this process. The buffer is checked in the same way as `Ipc::try_from_slice()`.
+    pub fn from_buffer(buffer: AlignedBuffer, signature: usize) -> Result<Self, DecodeError> {
+        T::try_from_slice(&buffer, signature)?;
+        Ok(IpcBox { buffer, _marker: PhantomData })
+    }
+
+    /// Move the contents of the box to the specified server using `transport`. The
+    /// allocation itself is handed over, so nothing is copied.
+    pub fn send_with<Tr: Transport + ?Sized>(
+        self,
+        transport: &Tr,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<(), backend::Error> {
+        transport.move_memory(connection, opcode.into(), T::SIGNATURE, self.into_buffer())
+    }
+
+    /// Move the contents of the box to the specified server without waiting for a response.
+    pub fn send(self, connection: CID, opcode: impl Into<usize>) -> Result<(), backend::Error> {
+        self.send_with(&DefaultTransport::default(), connection, opcode)
+    }
+
+    /// Give up the allocation without dropping the contents, which now belong to
+    /// whoever receives the buffer.
+    fn into_buffer(self) -> AlignedBuffer {
+        let this = ManuallyDrop::new(self);
+        unsafe { core::ptr::read(&this.buffer) }
+    }
+
+    /// Return a pointer to the contents of the box, which is page-aligned and
+    /// valid for `size_of::<T>()` bytes.
+    pub fn as_ptr(&self) -> *const T { self.buffer.as_ptr() as *const T }
+
+    /// Return a mutable pointer to the contents of the box, which is page-aligned
+    /// and valid for `size_of::<T>()` bytes.
+    pub fn as_mut_ptr(&mut self) -> *mut T { self.buffer.as_mut_ptr() as *mut T }
+}
+
+impl<T: Ipc> Drop for IpcBox<T> {
+    fn drop(&mut self) { unsafe { core::ptr::drop_in_place(self.as_mut_ptr()) } }
+}
+
+impl<T: Ipc> core::ops::Deref for IpcBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target { unsafe { &*self.as_ptr() } }
+}
+
+impl<T: Ipc> core::ops::DerefMut for IpcBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target { unsafe { &mut *self.as_mut_ptr() } }
+}
+
+impl<T: Ipc + core::fmt::Debug> core::fmt::Debug for IpcBox<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result { (**self).fmt(f) }
+}
+
+/// Types whose default value can be written straight into memory that has already
+/// been allocated, such as an `IpcBox`, without first being built on the stack.
+/// This is implemented by `#[derive(IpcSafe)]` and `#[derive(Ipc)]`, which set
+/// every field of a struct to its default in the same way as `#[derive(Default)]`,
+/// and is what `Ipc::default_in_place()` uses.
+///
+/// # Safety
+///
+/// `default_in_place()` must leave a valid `Self` behind.
+pub unsafe trait DefaultInPlace {
+    /// Write the default value of `Self` to `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned and valid for writes of `size_of::<Self>()` bytes.
+    unsafe fn default_in_place(ptr: *mut Self);
+}
+
+macro_rules! default_in_place_by_value {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl DefaultInPlace for $ty {
+                unsafe fn default_in_place(ptr: *mut Self) { unsafe { ptr.write(Default::default()) } }
+            }
+        )*
+    };
+}
+
+default_in_place_by_value!(
+    i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, f32, f64, usize, isize, bool, char
+);
+
+// Arrays are written one element at a time, since they may be far larger than a stack
+unsafe impl<T: DefaultInPlace, const N: usize> DefaultInPlace for [T; N] {
+    unsafe fn default_in_place(ptr: *mut Self) {
+        for i in 0..N {
+            unsafe { T::default_in_place((ptr as *mut T).add(i)) };
+        }
+    }
+}
+
+unsafe impl<T> DefaultInPlace for Option<T> {
+    unsafe fn default_in_place(ptr: *mut Self) { unsafe { ptr.write(None) } }
+}
+
+macro_rules! default_in_place_tuple {
+    ($(($($name:ident $index:tt),*)),*) => {
+        $(
+            unsafe impl<$($name: DefaultInPlace),*> DefaultInPlace for ($($name,)*) {
+                unsafe fn default_in_place(ptr: *mut Self) {
+                    $(unsafe { $name::default_in_place(core::ptr::addr_of_mut!((*ptr).$index)) };)*
+                }
+            }
+        )*
+    };
+}
+
+default_in_place_tuple!((A 0, B 1), (A 0, B 1, C 2), (A 0, B 1, C 2, D 3));
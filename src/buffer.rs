@@ -0,0 +1,136 @@
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+use crate::PAGE_SIZE;
+
+/// A zero-initialized, page-aligned buffer on the heap.
+///
+/// `Ipc` objects may only be reconstituted from page-aligned memory. Buffers
+/// that come from elsewhere, such as a `std::vec::Vec<u8>` in a host-side test,
+/// can be copied into an `AlignedBuffer` before being passed to `from_slice()`.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    align: usize,
+}
+
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate a new zeroed buffer that is `len` bytes long. The underlying
+    /// allocation is rounded up to a whole number of pages.
+    pub fn new(len: usize) -> Self { Self::with_alignment(len, PAGE_SIZE) }
+
+    /// Allocate a new zeroed buffer that is `len` bytes long and aligned to
+    /// `align` bytes, which must be a power of two. The buffer is always at least
+    /// page-aligned, which is needed for `Ipc` types with a larger `page_size`.
+    pub fn with_alignment(len: usize, align: usize) -> Self {
+        let align = align.max(PAGE_SIZE);
+        let ptr = unsafe { Self::allocate(Self::layout(len, align)) };
+        AlignedBuffer { ptr, len, align }
+    }
+
+    /// Allocate a new buffer containing a copy of `data`.
+    pub fn copy_from(data: &[u8]) -> Self {
+        let mut buffer = Self::new(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    fn layout(len: usize, align: usize) -> Layout {
+        let size = len.div_ceil(align).max(1) * align;
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[cfg(not(all(feature = "xous", target_os = "xous")))]
+    unsafe fn allocate(layout: Layout) -> NonNull<u8> {
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else { std::alloc::handle_alloc_error(layout) };
+        ptr
+    }
+
+    #[cfg(not(all(feature = "xous", target_os = "xous")))]
+    unsafe fn release(ptr: NonNull<u8>, layout: Layout) {
+        unsafe { std::alloc::dealloc(ptr.as_ptr(), layout) }
+    }
+
+    /// Pages are mapped directly from the kernel, which hands them out already zeroed.
+    /// The kernel only guarantees page alignment, so larger alignments come from the heap.
+    #[cfg(all(feature = "xous", target_os = "xous"))]
+    unsafe fn allocate(layout: Layout) -> NonNull<u8> {
+        if layout.align() > PAGE_SIZE {
+            let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+            let Some(ptr) = NonNull::new(ptr) else { std::alloc::handle_alloc_error(layout) };
+            return ptr;
+        }
+        let flags = ::xous::MemoryFlags::R | ::xous::MemoryFlags::W;
+        let Ok(range) = ::xous::map_memory(None, None, layout.size(), flags) else {
+            std::alloc::handle_alloc_error(layout)
+        };
+        NonNull::new(range.as_mut_ptr()).unwrap()
+    }
+
+    #[cfg(all(feature = "xous", target_os = "xous"))]
+    unsafe fn release(ptr: NonNull<u8>, layout: Layout) {
+        if layout.align() > PAGE_SIZE {
+            unsafe { std::alloc::dealloc(ptr.as_ptr(), layout) };
+        } else if let Ok(range) = unsafe { ::xous::MemoryRange::new(ptr.as_ptr() as usize, layout.size()) } {
+            ::xous::unmap_memory(range).ok();
+        }
+    }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// The alignment of the buffer, which is at least `PAGE_SIZE`.
+    pub fn align(&self) -> usize { self.align }
+
+    /// The size of the underlying allocation, which is a whole number of pages.
+    /// This is what is handed to another process when the buffer is moved.
+    pub fn capacity(&self) -> usize { Self::layout(self.len, self.align).size() }
+
+    /// Take ownership of memory that was moved into this process, which is
+    /// released in the same way as memory allocated by `with_alignment()`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated in the same way as a buffer of `len` bytes
+    /// aligned to `align`, such as by the kernel when a message is moved, and
+    /// must not be used by anything else.
+    #[cfg(feature = "xous")]
+    pub(crate) unsafe fn from_raw_parts(ptr: NonNull<u8>, len: usize, align: usize) -> Self {
+        AlignedBuffer { ptr, len, align }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) { unsafe { Self::release(self.ptr, Self::layout(self.len, self.align)) } }
+}
+
+impl core::ops::Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target { unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) } }
+}
+
+impl core::ops::DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Clone for AlignedBuffer {
+    fn clone(&self) -> Self {
+        let mut buffer = Self::with_alignment(self.len, self.align);
+        buffer.copy_from_slice(self);
+        buffer
+    }
+}
+
+impl core::fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AlignedBuffer").field("len", &self.len).finish()
+    }
+}
@@ -0,0 +1,98 @@
+//! Routing of lent messages to handlers for the types they contain.
+//!
+//! Servers that receive several types of message otherwise match on the opcode and
+//! decode each buffer by hand. A `Dispatcher` holds a handler for each opcode and
+//! `Ipc` type, checks that each request carries a valid object of the expected type,
+//! and replies with whatever the handler returns:
+//!
+//! ```ignore
+//! let mut dispatcher = Dispatcher::new();
+//! dispatcher
+//!     .handler::<IpcTextView>(DRAW, |tv: &mut TextView| draw(tv))
+//!     .handler::<IpcSimpleValue>(INCREMENT, |value: &mut SimpleValue| value.inner += 1);
+//!
+//! while let Ok(envelope) = handle.receive() {
+//!     if let Err(error) = dispatcher.dispatch(envelope) {
+//!         log::error!("{}", error);
+//!     }
+//! }
+//! ```
+//!
+//! Handlers are given mutable access to the lent object, so requests must be lent
+//! with `lend_mut()`. The argument of each closure must be annotated with its type.
+
+use std::collections::BTreeMap;
+
+use crate::service::{Reply, Request, ServiceError};
+use crate::{DecodeError, Ipc, Validate};
+
+/// A function that handles a lent object and returns the two values that the
+/// Server responds with. This is implemented for closures that take `&mut` the
+/// original type and return a `Reply`.
+pub trait Handler<A: ?Sized> {
+    /// Handle the object and pack the result into the Server's response.
+    fn call(&mut self, original: &mut A) -> (usize, usize);
+}
+
+impl<A: ?Sized, F, R: Reply> Handler<A> for F
+where
+    F: FnMut(&mut A) -> R,
+{
+    fn call(&mut self, original: &mut A) -> (usize, usize) { self(original).into_reply() }
+}
+
+type HandlerFn<'a> = Box<dyn FnMut(&mut [u8], usize) -> Result<(usize, usize), DecodeError> + 'a>;
+
+/// A table of handlers keyed by opcode and signature.
+#[derive(Default)]
+pub struct Dispatcher<'a> {
+    handlers: BTreeMap<(usize, usize), HandlerFn<'a>>,
+}
+
+impl<'a> Dispatcher<'a> {
+    /// Create a dispatcher with no handlers.
+    pub fn new() -> Self { Self::default() }
+
+    /// Call `handler` for requests with `opcode` that carry a `T`. Several types may
+    /// share an opcode, in which case the signature selects between them. Registering
+    /// the same opcode and type again replaces the earlier handler.
+    pub fn handler<T: Ipc + 'a>(
+        &mut self,
+        opcode: impl Into<usize>,
+        mut handler: impl Handler<T::Original> + 'a,
+    ) -> &mut Self
+    where
+        T::Original: Validate,
+    {
+        let handler = move |buffer: &mut [u8], signature| {
+            T::from_slice_mut_checked(buffer, signature).map(|ipc| handler.call(ipc.as_original_mut()))
+        };
+        self.handlers.insert((opcode.into(), T::SIGNATURE), Box::new(handler));
+        self
+    }
+
+    /// Pass `request` to the handler for its opcode and signature, and reply with the
+    /// result. Requests that no handler accepts are replied to with zeroes, and fail
+    /// with `ServiceError::UnknownOpcode` if nothing handles the opcode, or with
+    /// `DecodeError::SignatureMismatch` if nothing handles the type it carries.
+    pub fn dispatch<R: Request>(&mut self, mut request: R) -> Result<(), ServiceError> {
+        let result = self.call(&mut request);
+        let (a, b) = *result.as_ref().unwrap_or(&(0, 0));
+        request.reply(a, b);
+        result.map(|_| ())
+    }
+
+    fn call<R: Request>(&mut self, request: &mut R) -> Result<(usize, usize), ServiceError> {
+        let opcode = request.opcode();
+        let Some(&(_, expected)) =
+            self.handlers.range((opcode, 0)..=(opcode, usize::MAX)).next().map(|(key, _)| key)
+        else {
+            return Err(ServiceError::UnknownOpcode(opcode));
+        };
+        let (buffer, got) = request.lent_mut().ok_or(DecodeError::NotLent)?;
+        // When several types share the opcode, the first of their signatures is reported
+        let handler =
+            self.handlers.get_mut(&(opcode, got)).ok_or(DecodeError::SignatureMismatch { expected, got })?;
+        Ok(handler(buffer, got)?)
+    }
+}
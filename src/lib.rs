@@ -9,7 +9,8 @@
 //! such as full enums and structs without doing extensive checks. This is
 //! based on the theory that in normal operating systems, ABIs do not contain
 //! any sort of verification, and it is undefined behaviour to send a malformed
-//! request to a loaded module.
+//! request to a loaded module. Servers that cannot trust their clients may
+//! opt in to validating every field of a message with `from_slice_checked()`.
 //!
 //! An object can be made into an IPC object by implementing the `Ipc` trait.
 //! The primary method of doing this is by adding `#[derive(flatipc::Ipc)]`
@@ -26,8 +27,9 @@
 //!   implemented on primitive types that are able to be sent across an IPC
 //!   boundary. This includes all integers, floats, and booleans. It also
 //!   includes arrays of `IpcSafe` types, `Option<T>` where `T` is `IpcSafe`,
-//!   and `Result<T, E>` where `T` and `E` are `IpcSafe`. Pointers and
-//!   references are not `IpcSafe` and may not be used.
+//!   and `Result<T, E>` where `T` and `E` are `IpcSafe`, although `Result`
+//!   can't be validated. Pointers and references are not `IpcSafe` and may
+//!   not be used.
 //!
 //! When deriving `Ipc`, a new type will be created with the same name as
 //! the original type prefixed with `Ipc`. For example, if you derive `Ipc`
@@ -103,6 +105,12 @@
 //!     // returned and the message will be returned to the sender in ghe next loop.
 //!     let Some(foo) = IpcFoo::from_memory_message(msg_slice, signature) else { continue };
 //!
+//!     // Alternately, use `try_from_memory_message()` to find out why the message was rejected.
+//!     match IpcFoo::try_from_memory_message(msg_memory) {
+//!         Ok(foo) => println!("a: {}", foo.a),
+//!         Err(e) => println!("Unable to decode message: {}", e),
+//!     }
+//!
 //!     // Do something with the object.
 //! }
 //! ```
@@ -111,31 +119,157 @@
 /// This trait can be placed on objects that have invalid representations such as
 /// bools (which can only be 0 or 1) but it is up to the implementer to ensure that
 /// the correct object arrives on the other side.
-pub unsafe trait IpcSafe {}
+///
+/// # Safety
+///
+/// The type must not contain any pointers or references, and must have a layout
+/// that is identical between the Client and the Server.
+pub unsafe trait IpcSafe: Sized {
+    /// A fingerprint of the layout of this type, which is folded into the signature
+    /// of any type that contains it. `#[derive(IpcSafe)]` computes this from the
+    /// names of the type's fields and the signatures of their types. Types that
+    /// implement this trait by hand get a fingerprint of their size and alignment.
+    const SIGNATURE: u64 = signature::layout::<Self>();
+}
 
 // Enable calling this crate as `flatipc` in tests.
 extern crate self as flatipc;
 
 // Allow doing `#[derive(flatipc::Ipc)]` instead of `#[derive(flatipc_derive::Ipc)]`
-pub use flatipc_derive::{Ipc, IpcSafe};
+pub use flatipc_derive::{Ipc, IpcSafe, IpcScalar, Opcodes, message_set, service};
 #[cfg(feature = "xous")]
-mod backend {
-    pub use xous::Error;
-    pub use xous::CID;
+pub mod backend {
+    pub use ::xous::CID;
+    pub use ::xous::Error;
+
+    pub mod mock;
+    pub mod xous;
 }
 
 #[cfg(not(feature = "xous"))]
-mod backend {
+pub mod backend {
     pub mod mock;
-    pub use mock::CID;
+    pub use mock::{CID, Error};
+}
 
-    #[derive(Debug)]
-    pub enum Error {
-        Unimplemented,
-    }
+pub use backend::{CID, Error};
+
+/// The size of a page, which is the alignment and granularity of `Ipc` objects
+/// unless they specify `#[flatipc(page_size = ...)]`. This is 4096 bytes, or 16384
+/// bytes with the `page-size-16k` feature.
+#[cfg(not(feature = "page-size-16k"))]
+pub const PAGE_SIZE: usize = 4096;
+#[cfg(feature = "page-size-16k")]
+pub const PAGE_SIZE: usize = 16384;
+
+/// The `Transport` used by `Ipc::lend()` and friends when no transport is specified.
+/// This is the Xous kernel when the `xous` feature is enabled, and the mock machine otherwise.
+#[cfg(feature = "xous")]
+pub type DefaultTransport = backend::xous::Xous;
+#[cfg(not(feature = "xous"))]
+pub type DefaultTransport = backend::mock::Mock;
+
+/// The values a Server responds with when it returns a lent buffer. Servers may use
+/// these to signal a status code or the number of bytes written back without needing
+/// to encode them in the buffer. Values that the Server didn't specify are `0`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LendResult {
+    /// The first value returned by the Server. Xous calls this the `offset`.
+    pub offset: usize,
+
+    /// The second value returned by the Server. Xous calls this `valid`, and it is
+    /// conventionally the number of bytes of the buffer that are valid.
+    pub valid: usize,
+}
+
+/// A mechanism for getting messages from a Client to a Server. Code generated by
+/// `#[derive(Ipc)]` calls through this trait, which allows the same `Ipc` type to be
+/// sent using Xous, the mock backend, or a transport provided by the caller.
+pub trait Transport {
+    /// Lend `data` to the server. The server may read from the buffer but
+    /// may not modify it. Blocks until the server returns the buffer, and
+    /// returns the two values that the server responded with.
+    fn lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, Error>;
+
+    /// Lend `data` to the server, returning an error rather than blocking
+    /// if the server is not able to receive the message.
+    fn try_lend(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &[u8],
+    ) -> Result<LendResult, Error>;
+
+    /// Lend `data` to the server and allow the server to modify it. Blocks
+    /// until the server returns the buffer.
+    fn lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, Error>;
+
+    /// Mutably lend `data` to the server, returning an error rather than blocking
+    /// if the server is not able to receive the message.
+    fn try_lend_mut(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: &mut [u8],
+    ) -> Result<LendResult, Error>;
+
+    /// Send a scalar message to the server without waiting for a response.
+    fn send(&self, connection: CID, opcode: usize, args: [usize; 4]) -> Result<(), Error>;
+
+    /// Send a scalar message to the server and block until it responds.
+    fn scalar(&self, connection: CID, opcode: usize, args: [usize; 4]) -> Result<(usize, usize), Error>;
+
+    /// Move `data` to the server without waiting for a response. The buffer belongs to
+    /// the server once the message has been sent, and is released by the server.
+    fn move_memory(
+        &self,
+        connection: CID,
+        opcode: usize,
+        signature: usize,
+        data: AlignedBuffer,
+    ) -> Result<(), Error>;
+}
+
+/// A message received by a Server whose buffer may be taken over, as is the case for
+/// messages that were sent with `Ipc::send()`. This is implemented by the message
+/// envelopes of each backend, and is used by `Ipc::from_owned_message()`.
+pub trait OwnedMessage {
+    /// Take the buffer and the signature that accompanied it, or return `None` if the
+    /// buffer was lent rather than moved. The message is consumed either way.
+    fn take_buffer(self) -> Option<(AlignedBuffer, usize)>;
 }
 
-pub use backend::{Error, CID};
+pub mod boxed;
+pub use boxed::{DefaultInPlace, IpcBox};
+
+pub mod buffer;
+pub use buffer::AlignedBuffer;
+
+pub mod dispatch;
+pub use dispatch::Dispatcher;
+
+pub mod replay;
+
+pub mod scalar;
+pub use scalar::{IpcScalar, ScalarEnvelope};
+
+pub mod service;
+
+pub mod signature;
 
 pub mod string;
 pub use string::String;
@@ -143,54 +277,159 @@ pub use string::String;
 pub mod vec;
 pub use vec::Vec;
 
-unsafe impl IpcSafe for i8 {}
-unsafe impl IpcSafe for i16 {}
-unsafe impl IpcSafe for i32 {}
-unsafe impl IpcSafe for i64 {}
-unsafe impl IpcSafe for i128 {}
-unsafe impl IpcSafe for u8 {}
-unsafe impl IpcSafe for u16 {}
-unsafe impl IpcSafe for u32 {}
-unsafe impl IpcSafe for u64 {}
-unsafe impl IpcSafe for u128 {}
-unsafe impl IpcSafe for f32 {}
-unsafe impl IpcSafe for f64 {}
-unsafe impl IpcSafe for bool {}
-unsafe impl IpcSafe for usize {}
-unsafe impl IpcSafe for isize {}
-unsafe impl IpcSafe for char {}
-unsafe impl<T, const N: usize> IpcSafe for [T; N] where T: IpcSafe {}
-unsafe impl<T> IpcSafe for Option<T> where T: IpcSafe {}
+pub mod validate;
+pub use validate::Validate;
+
+macro_rules! primitive_ipc_safe {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl IpcSafe for $ty {
+                const SIGNATURE: u64 = signature::fnv1a(stringify!($ty).as_bytes());
+            }
+        )*
+    };
+}
+
+primitive_ipc_safe!(i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, f32, f64, bool, usize, isize, char);
+
+unsafe impl<T, const N: usize> IpcSafe for [T; N]
+where
+    T: IpcSafe,
+{
+    const SIGNATURE: u64 =
+        signature::combine(signature::combine(signature::fnv1a(b"[]"), T::SIGNATURE), N as u64);
+}
+unsafe impl<T> IpcSafe for Option<T>
+where
+    T: IpcSafe,
+{
+    const SIGNATURE: u64 = signature::combine(signature::fnv1a(b"Option"), T::SIGNATURE);
+}
 unsafe impl<T, E> IpcSafe for Result<T, E>
 where
     T: IpcSafe,
     E: IpcSafe,
 {
+    const SIGNATURE: u64 =
+        signature::combine(signature::combine(signature::fnv1a(b"Result"), T::SIGNATURE), E::SIGNATURE);
+}
+
+/// The reason a buffer could not be turned into an `Ipc` object.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer is smaller than the `Ipc` object.
+    TooShort { needed: usize, got: usize },
+
+    /// The signature that accompanied the buffer is not the signature of the `Ipc` object.
+    SignatureMismatch { expected: usize, got: usize },
+
+    /// The buffer is not aligned to a page boundary.
+    Misaligned,
+
+    /// The buffer contains a value that is not valid for the named field.
+    InvalidValue { field: &'static str },
+
+    /// The buffer was lent rather than moved, so it can't be taken over.
+    NotMoved,
+
+    /// A scalar value was expected, but the message is a memory message.
+    NotScalar,
+
+    /// A lent buffer was expected, but the message is a scalar message, or the buffer
+    /// was lent immutably and needs to be modified.
+    NotLent,
 }
 
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::TooShort { needed, got } => {
+                write!(f, "buffer is too short: needed {} bytes but got {}", needed, got)
+            }
+            DecodeError::SignatureMismatch { expected, got } => {
+                write!(f, "signature mismatch: expected {:#x} but got {:#x}", expected, got)
+            }
+            DecodeError::Misaligned => write!(f, "buffer is not page-aligned"),
+            DecodeError::InvalidValue { field } => write!(f, "invalid value for field `{}`", field),
+            DecodeError::NotMoved => write!(f, "buffer was lent rather than moved"),
+            DecodeError::NotScalar => write!(f, "message is not a scalar message"),
+            DecodeError::NotLent => write!(f, "message does not contain a suitably lent buffer"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 /// An object that can be sent across an IPC boundary, and can be reconstituted
 /// on the other side without copying. An object with this trait must be page-aligned,
 /// must be a multiple of the page size in length, and must not contain any pointers.
+///
+/// # Safety
+///
+/// Implementations must uphold the alignment and length requirements above, since
+/// the object is handed to other processes as a raw range of pages, and must begin
+/// with the original object. This trait should be implemented using
+/// `#[derive(flatipc::Ipc)]`.
 pub unsafe trait Ipc {
     /// What this memory message is a representation of. This is used to turn
     /// this object back into the original object.
     type Original;
 
+    /// The signature of this type. This is sent alongside the message and is checked
+    /// by the Server before the message is reconstituted. It may be pinned or versioned
+    /// with `#[flatipc(signature = ...)]` or `#[flatipc(version = ...)]`.
+    const SIGNATURE: usize;
+
     /// Create an Ipc variant from the original object. Succeeds only if
     /// the signature passed in matches the signature of `Original`.
-    fn from_slice<'a>(data: &'a [u8], signature: usize) -> Option<&'a Self>;
+    fn from_slice(data: &[u8], signature: usize) -> Option<&Self> {
+        Self::try_from_slice(data, signature).ok()
+    }
+
+    /// Create an Ipc variant from the original object, returning a `DecodeError`
+    /// that describes why the buffer was rejected if it is not suitable. `data`
+    /// must be page-aligned. Buffers that may not be aligned can be copied into
+    /// an `AlignedBuffer` first.
+    fn try_from_slice(data: &[u8], signature: usize) -> Result<&Self, DecodeError>;
 
     /// Unconditionally create a new memory message from the original object.
-    /// It is up to the caller to that `data` contains a valid representation of `Self`.
-    unsafe fn from_buffer_unchecked<'a>(data: &'a [u8]) -> &'a Self;
+    ///
+    /// # Safety
+    ///
+    /// It is up to the caller to ensure that `data` contains a valid representation of `Self`.
+    unsafe fn from_buffer_unchecked(data: &[u8]) -> &Self;
 
     /// Create a mutable IPC variant from the original object. Succeeds only if
     /// the signature passed in matches the signature of `Original`.
-    fn from_slice_mut<'a>(data: &'a mut [u8], signature: usize) -> Option<&'a mut Self>;
+    fn from_slice_mut(data: &mut [u8], signature: usize) -> Option<&mut Self> {
+        Self::try_from_slice_mut(data, signature).ok()
+    }
+
+    /// Create a mutable IPC variant from the original object, returning a `DecodeError`
+    /// that describes why the buffer was rejected if it is not suitable. `data`
+    /// must be page-aligned.
+    fn try_from_slice_mut(data: &mut [u8], signature: usize) -> Result<&mut Self, DecodeError>;
+
+    /// Create an Ipc variant from the original object, additionally verifying that
+    /// every field holds a valid value using `Validate`. This should be used when
+    /// the sender is not trusted. It is only available if the original object can
+    /// be validated.
+    fn from_slice_checked(data: &[u8], signature: usize) -> Result<&Self, DecodeError>
+    where
+        Self::Original: Validate;
+
+    /// Create a mutable IPC variant from the original object, additionally verifying
+    /// that every field holds a valid value using `Validate`.
+    fn from_slice_mut_checked(data: &mut [u8], signature: usize) -> Result<&mut Self, DecodeError>
+    where
+        Self::Original: Validate;
 
     /// Unconditionally create a new mutable memory message from the original object.
-    /// It is up to the caller to that `data` contains a valid representation of `Self`.
-    unsafe fn from_buffer_mut_unchecked<'a>(data: &'a mut [u8]) -> &'a mut Self;
+    ///
+    /// # Safety
+    ///
+    /// It is up to the caller to ensure that `data` contains a valid representation of `Self`.
+    unsafe fn from_buffer_mut_unchecked(data: &mut [u8]) -> &mut Self;
 
     /// Return a reference to the original object while keeping the
     /// memory version alive.
@@ -203,36 +442,165 @@ pub unsafe trait Ipc {
     /// Consume the memory version and return the original object.
     fn into_original(self) -> Self::Original;
 
+    /// Construct a new object directly in page-aligned memory on the heap. `init`
+    /// is handed the uninitialized original object, which it must fill in. Neither
+    /// the original object nor its padding are ever placed on the stack.
+    ///
+    /// # Safety
+    ///
+    /// `init` must fully initialize the original object before returning. Memory
+    /// starts out zeroed, so fields for which zero is a valid value may be skipped.
+    unsafe fn new_with<F>(init: F) -> IpcBox<Self>
+    where
+        Self: Sized,
+        F: FnOnce(&mut core::mem::MaybeUninit<Self::Original>);
+
+    /// Construct a new object on the heap with every field of the original object
+    /// set to its default value. The value is written field by field, and arrays
+    /// element by element, so it is never placed on the stack.
+    fn default_in_place() -> IpcBox<Self>
+    where
+        Self: Sized,
+        Self::Original: DefaultInPlace,
+    {
+        unsafe { Self::new_with(|original| Self::Original::default_in_place(original.as_mut_ptr())) }
+    }
+
+    /// Lend the buffer to the specified server using `transport`. The connection
+    /// should already be open and the server should be ready to receive the buffer.
+    /// Returns the values the server responded with when it returned the buffer.
+    fn lend_with<T: Transport + ?Sized>(
+        &self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<LendResult, backend::Error>;
+
+    /// Try to lend the buffer to the specified server using `transport`, returning
+    /// an error if the lend failed.
+    fn try_lend_with<T: Transport + ?Sized>(
+        &self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<LendResult, backend::Error>;
+
+    /// Lend the buffer to the specified server using `transport`, and allow the
+    /// server to modify the buffer.
+    fn lend_mut_with<T: Transport + ?Sized>(
+        &mut self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<LendResult, backend::Error>;
+
+    /// Lend the buffer to the specified server using `transport`, and allow the
+    /// server to modify the buffer. Return an error if the lend failed.
+    fn try_lend_mut_with<T: Transport + ?Sized>(
+        &mut self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<LendResult, backend::Error>;
+
     /// Lend the buffer to the specified server. The connection should already be
     /// open and the server should be ready to receive the buffer.
-    fn lend(&self, connection: CID, opcode: usize) -> Result<(), backend::Error>;
+    fn lend(&self, connection: CID, opcode: impl Into<usize>) -> Result<LendResult, backend::Error> {
+        self.lend_with(&DefaultTransport::default(), connection, opcode)
+    }
 
     /// Try to lend the buffer to the specified server, returning an error
     /// if the lend failed.
-    fn try_lend(&self, connection: CID, opcode: usize) -> Result<(), backend::Error>;
+    fn try_lend(&self, connection: CID, opcode: impl Into<usize>) -> Result<LendResult, backend::Error> {
+        self.try_lend_with(&DefaultTransport::default(), connection, opcode)
+    }
 
     /// Lend the buffer to the specified server, and allow the server to
     /// modify the buffer.
-    fn lend_mut(&mut self, connection: CID, opcode: usize) -> Result<(), backend::Error>;
+    fn lend_mut(&mut self, connection: CID, opcode: impl Into<usize>) -> Result<LendResult, backend::Error> {
+        self.lend_mut_with(&DefaultTransport::default(), connection, opcode)
+    }
 
     /// Lend the buffer to the specified server, and allow the server to
     /// modify the buffer. Return an error if the lend failed.
-    fn try_lend_mut(&mut self, connection: CID, opcode: usize) -> Result<(), backend::Error>;
+    fn try_lend_mut(
+        &mut self,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<LendResult, backend::Error> {
+        self.try_lend_mut_with(&DefaultTransport::default(), connection, opcode)
+    }
+
+    /// Move the object to the specified server using `transport`. Unlike lending, the
+    /// server takes ownership of the memory, and this returns as soon as the message
+    /// has been sent. The object is first copied to the heap, which may be avoided by
+    /// sending an `IpcBox` instead.
+    fn send_with<T: Transport + ?Sized>(
+        self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<(), backend::Error>
+    where
+        Self: Sized,
+    {
+        IpcBox::new(self).send_with(transport, connection, opcode)
+    }
+
+    /// Move the object to the specified server without waiting for a response.
+    fn send(self, connection: CID, opcode: impl Into<usize>) -> Result<(), backend::Error>
+    where
+        Self: Sized,
+    {
+        self.send_with(&DefaultTransport::default(), connection, opcode)
+    }
+
+    /// Take ownership of the buffer of a message that was sent with `send()`. Verifies
+    /// the signature, and releases the buffer if the message is rejected.
+    fn from_owned_message<M: OwnedMessage>(message: M) -> Result<IpcBox<Self>, DecodeError>
+    where
+        Self: Sized,
+    {
+        let (buffer, signature) = message.take_buffer().ok_or(DecodeError::NotMoved)?;
+        IpcBox::from_buffer(buffer, signature)
+    }
 
     /// Return the signature of this memory message. Useful for verifying
     /// that the correct message is being received.
-    fn signature(&self) -> usize;
+    fn signature(&self) -> usize { Self::SIGNATURE }
 
     #[cfg(feature = "xous")]
     /// Build an `Ipc` object from a `xous::MemoryMessage`. Verifies the signature and
     /// returns `None` if there is no match.
-    fn from_memory_message<'a>(msg: &'a xous::MemoryMessage) -> Option<&'a Self>;
+    fn from_memory_message(msg: &xous::MemoryMessage) -> Option<&Self> {
+        Self::try_from_memory_message(msg).ok()
+    }
+
+    #[cfg(feature = "xous")]
+    /// Build an `Ipc` object from a `xous::MemoryMessage`, returning a `DecodeError`
+    /// that describes why the message was rejected if it is not suitable.
+    fn try_from_memory_message(msg: &xous::MemoryMessage) -> Result<&Self, DecodeError> {
+        let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
+        let data = unsafe { core::slice::from_raw_parts(msg.buf.as_ptr(), msg.buf.len()) };
+        Self::try_from_slice(data, signature)
+    }
 
     #[cfg(feature = "xous")]
     /// Build a mutable `Ipc` object from a mutable `xous::MemoryMessage`. Verifies the
     /// signature and returns `None` if there is no match. The returned object has a
     /// lifetime that's tied to the `MemoryMessage`.
-    fn from_memory_message_mut<'a>(msg: &'a mut xous::MemoryMessage) -> Option<&'a mut Self>;
+    fn from_memory_message_mut(msg: &mut xous::MemoryMessage) -> Option<&mut Self> {
+        Self::try_from_memory_message_mut(msg).ok()
+    }
+
+    #[cfg(feature = "xous")]
+    /// Build a mutable `Ipc` object from a mutable `xous::MemoryMessage`, returning a
+    /// `DecodeError` that describes why the message was rejected if it is not suitable.
+    fn try_from_memory_message_mut(msg: &mut xous::MemoryMessage) -> Result<&mut Self, DecodeError> {
+        let signature = msg.offset.map(|offset| offset.get()).unwrap_or_default();
+        let data = unsafe { core::slice::from_raw_parts_mut(msg.buf.as_mut_ptr(), msg.buf.len()) };
+        Self::try_from_slice_mut(data, signature)
+    }
 }
 
 /// Objects that have `IntoIpc` may be turned into an object that can be passed
@@ -241,6 +609,17 @@ pub unsafe trait Ipc {
 pub trait IntoIpc {
     type IpcType;
     fn into_ipc(self) -> Self::IpcType;
+
+    /// Turn this object into an `Ipc` object that lives on the heap rather than
+    /// on the stack. `#[derive(Ipc)]` constructs the object directly in the
+    /// allocation so that no page-sized temporary is placed on the stack.
+    fn into_ipc_boxed(self) -> IpcBox<Self::IpcType>
+    where
+        Self: Sized,
+        Self::IpcType: Ipc,
+    {
+        IpcBox::new(self.into_ipc())
+    }
 }
 
 #[cfg(test)]
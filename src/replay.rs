@@ -0,0 +1,301 @@
+//! Captures of IPC traffic that can be saved to disk and replayed.
+//!
+//! A `Capture` is a list of the messages a client sent, along with the buffers
+//! and results that the server returned. Captures are usually made by recording
+//! a mock machine with `Recorder::capture()`, and may be written to a file when a
+//! test fails. `Capture::replay()` later sends the same messages to a server over
+//! any `Transport` and checks that it responds in the same way, which allows a
+//! failing interaction to be reproduced without the original client.
+//!
+//! # File Format
+//!
+//! All integers are little-endian. A capture begins with the four bytes `FIPC`
+//! and a `u16` format version, followed by a `u16` that is reserved and zero.
+//! Each message then follows until the end of the file:
+//!
+//! | Size      | Contents                                                 |
+//! |-----------|----------------------------------------------------------|
+//! | `u64`     | Nanoseconds since the capture started                    |
+//! | `u64`     | Connection ID                                            |
+//! | `u64`     | Opcode                                                   |
+//! | `u8`      | `MessageKind`: 0 to 4, in the order they are declared    |
+//! | `u8`      | 1 if the message succeeded, 0 if it failed               |
+//! | `4 * u64` | Arguments, of which the first is the signature for lends |
+//! | `2 * u64` | Result, or zero if the message failed                    |
+//! | `u32`     | Length of the payload, or `u32::MAX` for scalars         |
+//! | ...       | Payload                                                  |
+//! | `u32`     | Length of the response, or `u32::MAX` if there is none   |
+//! | ...       | Response                                                 |
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::{AlignedBuffer, CID, LendResult, Transport};
+
+/// The first four bytes of every capture file.
+pub const MAGIC: [u8; 4] = *b"FIPC";
+
+/// The version of the file format written by `Capture::write_to()`.
+pub const VERSION: u16 = 1;
+
+/// The length written in place of a buffer for messages that have none.
+const NO_BUFFER: u32 = u32::MAX;
+
+/// The kind of a message, as held in a mock `Envelope` or a `CapturedMessage`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A buffer that the server may read. The client is blocked until it is returned.
+    Borrow,
+
+    /// A buffer that the server may modify. The client is blocked until it is returned.
+    MutableBorrow,
+
+    /// Four arguments. The client has already moved on.
+    Scalar,
+
+    /// Four arguments. The client is blocked until the server replies.
+    BlockingScalar,
+
+    /// A buffer that now belongs to the server. The client has already moved on.
+    Move,
+}
+
+impl MessageKind {
+    const ALL: [MessageKind; 5] = [
+        MessageKind::Borrow,
+        MessageKind::MutableBorrow,
+        MessageKind::Scalar,
+        MessageKind::BlockingScalar,
+        MessageKind::Move,
+    ];
+}
+
+/// A single message in a `Capture`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedMessage {
+    /// How long after the capture started the message was sent.
+    pub time: Duration,
+
+    /// The connection the message was sent to.
+    pub connection: CID,
+
+    /// The opcode the message was sent with.
+    pub opcode: usize,
+
+    /// What sort of message this was.
+    pub kind: MessageKind,
+
+    /// The arguments of the message. For lent buffers, the first argument is the
+    /// signature and the remainder are zero.
+    pub args: [usize; 4],
+
+    /// The buffer that was lent to the server, or `None` for scalar messages.
+    pub payload: Option<Vec<u8>>,
+
+    /// The buffer as it was returned to the client, or `None` for scalar messages and
+    /// for buffers that were moved to the server.
+    pub response: Option<Vec<u8>>,
+
+    /// What the server responded with, or `None` if the message failed.
+    pub result: Option<(usize, usize)>,
+}
+
+impl CapturedMessage {
+    /// The signature of a lent buffer, or `None` for scalar messages.
+    pub fn signature(&self) -> Option<usize> { self.payload.as_ref().map(|_| self.args[0]) }
+}
+
+/// A difference between a replayed message and the capture it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The server responded with a different result, or failed when it had succeeded.
+    Result { index: usize, expected: Option<(usize, usize)>, got: Option<(usize, usize)> },
+
+    /// The server returned a different buffer.
+    Response { index: usize, expected: Vec<u8>, got: Vec<u8> },
+}
+
+impl core::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Mismatch::Result { index, expected, got } => {
+                write!(f, "message {} returned {:?}, expected {:?}", index, got, expected)
+            }
+            Mismatch::Response { index, .. } => write!(f, "message {} returned a different buffer", index),
+        }
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// A recording of the messages sent to one or more servers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capture {
+    /// Every message, in the order it was sent.
+    pub messages: Vec<CapturedMessage>,
+}
+
+impl Capture {
+    /// Write the capture in the format described in the module documentation.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        for message in &self.messages {
+            let time = u64::try_from(message.time.as_nanos()).map_err(invalid_input)?;
+            // `CID` is narrower than 64 bits on Xous and wider in the mock
+            #[allow(clippy::unnecessary_fallible_conversions)]
+            let connection = u64::try_from(message.connection).map_err(invalid_input)?;
+            writer.write_all(&time.to_le_bytes())?;
+            writer.write_all(&connection.to_le_bytes())?;
+            writer.write_all(&(message.opcode as u64).to_le_bytes())?;
+            writer.write_all(&[message.kind as u8, message.result.is_some() as u8])?;
+            let (a, b) = message.result.unwrap_or_default();
+            for value in message.args.into_iter().chain([a, b]) {
+                writer.write_all(&(value as u64).to_le_bytes())?;
+            }
+            write_buffer(&mut writer, message.payload.as_deref())?;
+            write_buffer(&mut writer, message.response.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Read a capture that was written by `write_to()`.
+    pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid_data("not a flatipc capture"));
+        }
+        if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+            return Err(invalid_data("unsupported capture version"));
+        }
+
+        let mut messages = Vec::new();
+        // The end of the file may only come between messages
+        while let Some(time) = read_time(&mut reader)? {
+            #[allow(clippy::unnecessary_fallible_conversions)]
+            let connection = CID::try_from(read_u64(&mut reader)?).map_err(invalid_data)?;
+            let opcode = read_usize(&mut reader)?;
+            let mut flags = [0u8; 2];
+            reader.read_exact(&mut flags)?;
+            let kind =
+                *MessageKind::ALL.get(flags[0] as usize).ok_or_else(|| invalid_data("invalid kind"))?;
+            let mut args = [0usize; 4];
+            for arg in &mut args {
+                *arg = read_usize(&mut reader)?;
+            }
+            let result = (read_usize(&mut reader)?, read_usize(&mut reader)?);
+            let result = (flags[1] != 0).then_some(result);
+            let payload = read_buffer(&mut reader)?;
+            let response = read_buffer(&mut reader)?;
+            messages.push(CapturedMessage {
+                time,
+                connection,
+                opcode,
+                kind,
+                args,
+                payload,
+                response,
+                result,
+            });
+        }
+        Ok(Capture { messages })
+    }
+
+    /// Send every message in the capture to `connection`, and check that the server
+    /// returns the same results and buffers that it did when the capture was made.
+    /// Messages are sent one at a time and as quickly as possible, regardless of when
+    /// they were originally sent, and the connection they were originally sent to is
+    /// ignored. Replay stops at the first message that differs.
+    pub fn replay(&self, transport: &impl Transport, connection: CID) -> Result<(), Mismatch> {
+        for (index, message) in self.messages.iter().enumerate() {
+            let (opcode, args) = (message.opcode, message.args);
+            let mut buffer = AlignedBuffer::copy_from(message.payload.as_deref().unwrap_or_default());
+            let lent = |result: LendResult| (result.offset, result.valid);
+            let got = match message.kind {
+                MessageKind::Borrow => transport.lend(connection, opcode, args[0], &buffer).ok().map(lent),
+                MessageKind::MutableBorrow => {
+                    transport.lend_mut(connection, opcode, args[0], &mut buffer).ok().map(lent)
+                }
+                MessageKind::Scalar => transport.send(connection, opcode, args).ok().map(|()| (0, 0)),
+                MessageKind::BlockingScalar => transport.scalar(connection, opcode, args).ok(),
+                MessageKind::Move => {
+                    transport.move_memory(connection, opcode, args[0], buffer.clone()).ok().map(|()| (0, 0))
+                }
+            };
+            if got != message.result {
+                return Err(Mismatch::Result { index, expected: message.result, got });
+            }
+            if let Some(expected) = &message.response {
+                if message.result.is_some() && buffer[..] != expected[..] {
+                    return Err(Mismatch::Response {
+                        index,
+                        expected: expected.clone(),
+                        got: buffer.to_vec(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_buffer(writer: &mut impl Write, buffer: Option<&[u8]>) -> io::Result<()> {
+    let Some(buffer) = buffer else { return writer.write_all(&NO_BUFFER.to_le_bytes()) };
+    let len = u32::try_from(buffer.len())
+        .ok()
+        .filter(|&len| len != NO_BUFFER)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "buffer is too large to be captured"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(buffer)
+}
+
+fn read_buffer(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len == NO_BUFFER {
+        return Ok(None);
+    }
+    let mut buffer = Vec::new();
+    reader.take(len.into()).read_to_end(&mut buffer)?;
+    if buffer.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(buffer))
+}
+
+/// Read the timestamp that starts each message, or `None` at the end of the file.
+fn read_time(reader: &mut impl Read) -> io::Result<Option<Duration>> {
+    let mut bytes = [0u8; 8];
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match reader.read(&mut bytes[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(count) => filled += count,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(Some(Duration::from_nanos(u64::from_le_bytes(bytes))))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_usize(reader: &mut impl Read) -> io::Result<usize> {
+    usize::try_from(read_u64(reader)?).map_err(invalid_data)
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn invalid_input(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
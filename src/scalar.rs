@@ -0,0 +1,116 @@
+//! Small values that are sent in the arguments of a scalar message.
+//!
+//! Lending even a single `u32` maps a whole page into the Server. Values that fit
+//! into the four `usize` arguments of a scalar message may instead be copied into
+//! those arguments, which avoids touching the page tables entirely. Scalar messages
+//! have no room for a signature, so only the validity of the value is checked when
+//! it is received.
+
+use core::mem::{MaybeUninit, size_of};
+
+use crate::{CID, DecodeError, DefaultTransport, IpcSafe, Transport, Validate, backend};
+
+/// The number of bytes that fit in the arguments of a scalar message.
+pub const SCALAR_SIZE: usize = size_of::<[usize; 4]>();
+
+/// A value that can be sent in the arguments of a scalar message. Values are copied
+/// byte-for-byte into the arguments, so the first argument holds the first
+/// `size_of::<usize>()` bytes of the value and any unused bytes are zero.
+///
+/// # Safety
+///
+/// The type must be no larger than `SCALAR_SIZE` and must not contain any padding,
+/// since every byte of the value is copied into the arguments. This trait should be
+/// implemented using `#[derive(flatipc::IpcScalar)]`, which checks both.
+pub unsafe trait IpcScalar: IpcSafe + Validate + Sized {
+    /// Pack the value into the arguments of a scalar message.
+    fn to_args(&self) -> [usize; 4] {
+        const { assert!(size_of::<Self>() <= SCALAR_SIZE, "type is too large to be sent as a scalar") };
+        let mut args = [0usize; 4];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self as *const Self as *const u8,
+                args.as_mut_ptr() as *mut u8,
+                size_of::<Self>(),
+            )
+        };
+        args
+    }
+
+    /// Unpack a value from the arguments of a scalar message, checking that it is
+    /// valid using `Validate`.
+    fn from_args(args: [usize; 4]) -> Result<Self, DecodeError> {
+        const { assert!(size_of::<Self>() <= SCALAR_SIZE, "type is too large to be sent as a scalar") };
+        let mut value = MaybeUninit::<Self>::uninit();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                args.as_ptr() as *const u8,
+                value.as_mut_ptr() as *mut u8,
+                size_of::<Self>(),
+            );
+            Self::validate(value.as_ptr())?;
+            Ok(value.assume_init())
+        }
+    }
+
+    /// Send the value to the specified server using `transport` without waiting
+    /// for a response.
+    fn send_scalar_with<T: Transport + ?Sized>(
+        &self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<(), backend::Error> {
+        transport.send(connection, opcode.into(), self.to_args())
+    }
+
+    /// Send the value to the specified server using `transport`, and block until
+    /// the server responds with two values.
+    fn blocking_scalar_with<T: Transport + ?Sized>(
+        &self,
+        transport: &T,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<(usize, usize), backend::Error> {
+        transport.scalar(connection, opcode.into(), self.to_args())
+    }
+
+    /// Send the value to the specified server without waiting for a response.
+    fn send_scalar(&self, connection: CID, opcode: impl Into<usize>) -> Result<(), backend::Error> {
+        self.send_scalar_with(&DefaultTransport::default(), connection, opcode)
+    }
+
+    /// Send the value to the specified server and block until it responds.
+    fn blocking_scalar(
+        &self,
+        connection: CID,
+        opcode: impl Into<usize>,
+    ) -> Result<(usize, usize), backend::Error> {
+        self.blocking_scalar_with(&DefaultTransport::default(), connection, opcode)
+    }
+
+    /// Unpack a value from a scalar message received by a Server. Fails with
+    /// `DecodeError::NotScalar` if the message is a memory message.
+    fn from_scalar_message<M: ScalarEnvelope + ?Sized>(message: &M) -> Result<Self, DecodeError> {
+        Self::from_args(message.scalar_args().ok_or(DecodeError::NotScalar)?)
+    }
+}
+
+/// A message received by a Server that may carry scalar arguments. This is
+/// implemented by the message types of each backend, and is used by
+/// `IpcScalar::from_scalar_message()`.
+pub trait ScalarEnvelope {
+    /// The four arguments of the message, or `None` if it is a memory message.
+    fn scalar_args(&self) -> Option<[usize; 4]>;
+}
+
+macro_rules! impl_ipc_scalar {
+    ($($ty:ty),*) => {
+        $(unsafe impl IpcScalar for $ty {})*
+    };
+}
+
+impl_ipc_scalar!(i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, usize, isize, f32, f64, bool, char);
+
+// Arrays have no padding between their elements
+unsafe impl<T: IpcScalar, const N: usize> IpcScalar for [T; N] {}
@@ -0,0 +1,136 @@
+//! Support for services generated by `#[flatipc::service]`.
+//!
+//! A service is a trait whose methods each take at most one argument. The macro
+//! generates an opcode for each method, a client stub that sends the argument to
+//! the Server, and a `dispatch()` method that decodes and validates a request and
+//! calls the Server's implementation of the trait:
+//!
+//! ```ignore
+//! #[flatipc::service]
+//! pub trait Gfx {
+//!     fn draw(&mut self, tv: &mut TextView);
+//!     fn clear(&self, rect: Rectangle) -> u32;
+//! }
+//!
+//! // In the client
+//! let gfx = GfxClient::new(connection);
+//! gfx.draw(&mut text_view)?;
+//!
+//! // In the server
+//! while let Ok(envelope) = handle.receive() {
+//!     server.dispatch(envelope)?;
+//! }
+//! ```
+//!
+//! Arguments passed by reference are lent to the Server as `Ipc` objects, mutably
+//! if the reference is mutable, and arguments passed by value must be `IpcScalar`
+//! and are sent as blocking scalar messages. Return values are sent back in the two
+//! values that the Server responds with, and must be `Reply`.
+//!
+//! Opcodes are hashed from the names of the methods, so they don't change when
+//! methods are added or reordered. A method may instead be given `#[opcode = N]`,
+//! in which case every method must have one.
+
+use core::mem::size_of;
+
+use crate::{DecodeError, Ipc, IpcScalar, ScalarEnvelope, Validate};
+
+/// The ways in which a call to a service may fail.
+#[derive(Debug, PartialEq)]
+pub enum ServiceError {
+    /// The message could not be sent.
+    Transport(crate::Error),
+
+    /// The request or its response could not be decoded.
+    Decode(DecodeError),
+
+    /// The Server received an opcode that isn't part of the service.
+    UnknownOpcode(usize),
+}
+
+impl From<DecodeError> for ServiceError {
+    fn from(error: DecodeError) -> Self { ServiceError::Decode(error) }
+}
+
+impl From<crate::Error> for ServiceError {
+    fn from(error: crate::Error) -> Self { ServiceError::Transport(error) }
+}
+
+impl core::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ServiceError::Transport(error) => write!(f, "message could not be sent: {:?}", error),
+            ServiceError::Decode(error) => write!(f, "message could not be decoded: {}", error),
+            ServiceError::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// A message received by a Server that can be passed to a generated `dispatch()`.
+/// This is implemented by the message envelopes of each backend.
+pub trait Request: ScalarEnvelope {
+    /// The opcode the message was sent with.
+    fn opcode(&self) -> usize;
+
+    /// The lent buffer and the signature that accompanied it, if this is a memory message.
+    fn lent(&self) -> Option<(&[u8], usize)>;
+
+    /// The lent buffer and the signature that accompanied it, if this is a memory
+    /// message that the Server is allowed to modify.
+    fn lent_mut(&mut self) -> Option<(&mut [u8], usize)>;
+
+    /// Respond to the client with two values, returning its buffer if one was lent.
+    fn reply(self, a: usize, b: usize);
+}
+
+/// A value that can be returned from a service method. The value is packed into
+/// the two values that the Server responds with.
+pub trait Reply: Sized {
+    /// Pack the value into the two values that the Server responds with.
+    fn into_reply(self) -> (usize, usize);
+
+    /// Unpack a value that was returned by the Server.
+    fn from_reply(a: usize, b: usize) -> Result<Self, DecodeError>;
+}
+
+impl Reply for () {
+    fn into_reply(self) -> (usize, usize) { (0, 0) }
+
+    fn from_reply(_a: usize, _b: usize) -> Result<Self, DecodeError> { Ok(()) }
+}
+
+impl<T: IpcScalar> Reply for T {
+    fn into_reply(self) -> (usize, usize) {
+        const { assert!(size_of::<T>() <= 2 * size_of::<usize>(), "type is too large to be returned") };
+        let [a, b, ..] = self.to_args();
+        (a, b)
+    }
+
+    fn from_reply(a: usize, b: usize) -> Result<Self, DecodeError> {
+        const { assert!(size_of::<T>() <= 2 * size_of::<usize>(), "type is too large to be returned") };
+        T::from_args([a, b, 0, 0])
+    }
+}
+
+/// Decode the original object that was lent with `request`, verifying that every
+/// field of it holds a valid value.
+pub fn lent<'a, T: Ipc + 'a, R: Request + ?Sized>(request: &'a R) -> Result<&'a T::Original, DecodeError>
+where
+    T::Original: Validate,
+{
+    let (buffer, signature) = request.lent().ok_or(DecodeError::NotLent)?;
+    T::from_slice_checked(buffer, signature).map(T::as_original)
+}
+
+/// Decode and validate the original object that was mutably lent with `request`.
+pub fn lent_mut<'a, T: Ipc + 'a, R: Request + ?Sized>(
+    request: &'a mut R,
+) -> Result<&'a mut T::Original, DecodeError>
+where
+    T::Original: Validate,
+{
+    let (buffer, signature) = request.lent_mut().ok_or(DecodeError::NotLent)?;
+    T::from_slice_mut_checked(buffer, signature).map(T::as_original_mut)
+}
@@ -0,0 +1,55 @@
+//! Stable type signatures.
+//!
+//! Every `Ipc` type carries a signature that is passed alongside the message so
+//! that the Server can verify it is looking at the type it expects. The signature
+//! is computed at compile time from a description of the type's layout using
+//! 64-bit FNV-1a, which is fixed and will not change between Rust releases.
+//! This means a Client and a Server built with different toolchains will agree on
+//! the signature, and editing comments or attributes won't change the wire format.
+//!
+//! Signatures are recursive: every `IpcSafe` type has a `SIGNATURE` of its own, and
+//! the signature of a type includes the signatures of all of its fields. Changing
+//! the layout of a nested type therefore changes the signature of every `Ipc` type
+//! that contains it.
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hash `bytes` using 64-bit FNV-1a.
+pub const fn fnv1a(bytes: &[u8]) -> u64 { extend(FNV_OFFSET_BASIS, bytes) }
+
+/// Continue an FNV-1a hash with additional bytes.
+const fn extend(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// Mix the signature of a member into the signature of its parent.
+pub const fn combine(hash: u64, member: u64) -> u64 { extend(hash, &member.to_le_bytes()) }
+
+/// A fingerprint of the size and alignment of `T`. This is used as the signature
+/// of types that implement `IpcSafe` by hand without providing a signature.
+pub const fn layout<T>() -> u64 {
+    let hash = combine(fnv1a(b"layout"), core::mem::size_of::<T>() as u64);
+    combine(hash, core::mem::align_of::<T>() as u64)
+}
+
+/// Reduce a 64-bit signature to the native word size so that it fits in
+/// the `offset` field of a message.
+pub const fn fold(hash: u64) -> usize {
+    #[cfg(target_pointer_width = "64")]
+    {
+        hash as usize
+    }
+    #[cfg(target_pointer_width = "32")]
+    {
+        (((hash >> 32) as u32) ^ (hash as u32)) as usize
+    }
+    #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
+    compile_error!("Unsupported target_pointer_width");
+}
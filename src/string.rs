@@ -4,11 +4,35 @@ pub struct String<const N: usize> {
     buffer: [u8; N],
 }
 
-unsafe impl<const N: usize> crate::IpcSafe for String<N> {}
+unsafe impl<const N: usize> crate::IpcSafe for String<N> {
+    const SIGNATURE: u64 = crate::signature::combine(crate::signature::fnv1a(b"flatipc::String"), N as u64);
+}
+
+unsafe impl<const N: usize> crate::Validate for String<N> {
+    unsafe fn validate(ptr: *const Self) -> Result<(), crate::DecodeError> {
+        // Every bit pattern of `length` and `buffer` is valid, so it's safe to
+        // take a reference before checking the contents.
+        let string = unsafe { &*ptr };
+        if string.length > N || core::str::from_utf8(&string.buffer[..string.length]).is_err() {
+            return Err(crate::DecodeError::InvalidValue { field: "" });
+        }
+        Ok(())
+    }
+}
+
+unsafe impl<const N: usize> crate::DefaultInPlace for String<N> {
+    unsafe fn default_in_place(ptr: *mut Self) {
+        unsafe {
+            core::ptr::addr_of_mut!((*ptr).length).write(0);
+            <[u8; N]>::default_in_place(core::ptr::addr_of_mut!((*ptr).buffer));
+        }
+    }
+}
 
 impl<const N: usize> String<N> {
     pub fn new() -> Self { String { buffer: [0; N], length: 0 } }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         let mut buffer = [0; N];
         let length = s.len();
@@ -1,3 +1,6 @@
+// These types mirror the graphics server API and are not all exercised by tests.
+#![allow(dead_code)]
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Default, flatipc::IpcSafe)]
 pub struct Point {
     pub x: i16,
@@ -16,7 +19,7 @@ impl From<bool> for PixelColor {
 }
 
 impl From<PixelColor> for bool {
-    fn from(pc: PixelColor) -> bool { if pc == PixelColor::Dark { true } else { false } }
+    fn from(pc: PixelColor) -> bool { pc == PixelColor::Dark }
 }
 
 impl From<usize> for PixelColor {
@@ -254,12 +257,12 @@ fn server_test() {
         flatipc::backend::mock::IPC_MACHINE.lock().unwrap().add_server(adder_server);
     let mut lendable_inc = inc.into_ipc();
     println!("Value before: {}", lendable_inc.value);
-    lendable_inc.lend(adder_server_connection, 0).unwrap();
+    lendable_inc.lend_with(&flatipc::backend::mock::Mock, adder_server_connection, 0usize).unwrap();
     println!("Value after: {}", lendable_inc.value);
 
     // Mutably lend the value and make sure the server can change the original
     println!("Value before mut: {}", lendable_inc.value);
-    lendable_inc.lend_mut(adder_server_connection, 0).unwrap();
+    lendable_inc.lend_mut_with(&flatipc::backend::mock::Mock, adder_server_connection, 0usize).unwrap();
     println!("Value after mut: {}", lendable_inc.value);
 
     println!("Does lendable_inc equal inc? {}", *lendable_inc == Incrementer { value: 43 });
@@ -268,3 +271,1640 @@ fn server_test() {
     let original_inc = lendable_inc.into_original();
     println!("Original value: {}", original_inc.value);
 }
+
+#[test]
+fn custom_transport() {
+    use std::cell::RefCell;
+
+    use flatipc::{CID, Error, IntoIpc, Ipc, LendResult, Transport};
+
+    /// A transport that runs an in-process server, doubling the value it's lent.
+    #[derive(Default)]
+    struct Doubler {
+        lent: RefCell<std::vec::Vec<(usize, usize, usize)>>,
+    }
+
+    impl Transport for Doubler {
+        fn lend(
+            &self,
+            _connection: CID,
+            opcode: usize,
+            signature: usize,
+            data: &[u8],
+        ) -> Result<LendResult, Error> {
+            self.lent.borrow_mut().push((opcode, signature, data.len()));
+            Ok(LendResult::default())
+        }
+
+        fn try_lend(
+            &self,
+            connection: CID,
+            opcode: usize,
+            signature: usize,
+            data: &[u8],
+        ) -> Result<LendResult, Error> {
+            self.lend(connection, opcode, signature, data)
+        }
+
+        fn lend_mut(
+            &self,
+            connection: CID,
+            opcode: usize,
+            signature: usize,
+            data: &mut [u8],
+        ) -> Result<LendResult, Error> {
+            self.lend(connection, opcode, signature, data)?;
+            let value = IpcDoubled::from_slice_mut(data, signature).unwrap();
+            value.0 *= 2;
+            Ok(LendResult { offset: 0, valid: data.len() })
+        }
+
+        fn try_lend_mut(
+            &self,
+            connection: CID,
+            opcode: usize,
+            signature: usize,
+            data: &mut [u8],
+        ) -> Result<LendResult, Error> {
+            self.lend_mut(connection, opcode, signature, data)
+        }
+
+        fn send(&self, _connection: CID, _opcode: usize, _args: [usize; 4]) -> Result<(), Error> { Ok(()) }
+
+        fn move_memory(
+            &self,
+            _connection: CID,
+            opcode: usize,
+            signature: usize,
+            data: flatipc::AlignedBuffer,
+        ) -> Result<(), Error> {
+            self.lent.borrow_mut().push((opcode, signature, data.len()));
+            Ok(())
+        }
+
+        fn scalar(
+            &self,
+            _connection: CID,
+            _opcode: usize,
+            args: [usize; 4],
+        ) -> Result<(usize, usize), Error> {
+            Ok((args[0] * 2, 0))
+        }
+    }
+
+    #[derive(flatipc::Ipc, Debug, PartialEq)]
+    #[repr(C)]
+    struct Doubled(u32);
+
+    let transport = Doubler::default();
+    let mut value = Doubled(21).into_ipc();
+    value.lend_with(&transport, 0, 7usize).unwrap();
+    assert_eq!(value.0, 21);
+    let result = value.lend_mut_with(&transport, 0, 8usize).unwrap();
+    assert_eq!(value.0, 42);
+    assert_eq!(result, LendResult { offset: 0, valid: flatipc::PAGE_SIZE });
+
+    let signature = value.signature();
+    assert_eq!(
+        *transport.lent.borrow(),
+        [(7, signature, flatipc::PAGE_SIZE), (8, signature, flatipc::PAGE_SIZE)]
+    );
+}
+
+#[test]
+fn stable_signatures() {
+    use flatipc::{IntoIpc, Ipc};
+
+    assert_eq!(flatipc::signature::fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(flatipc::signature::fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(flatipc::signature::fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+
+    mod original {
+        #[derive(flatipc::Ipc)]
+        #[repr(C)]
+        pub struct Message {
+            pub a: u32,
+            pub b: u64,
+        }
+    }
+
+    mod documented {
+        /// The same message, but with documentation, different visibility,
+        /// and extra formatting.
+        #[derive(flatipc::Ipc, Debug)]
+        #[repr(C)]
+        pub struct Message {
+            /// The first field
+            pub(crate) a: u32,
+            b: u64,
+        }
+
+        pub fn new() -> Message { Message { a: 0, b: 0 } }
+    }
+
+    mod renamed {
+        #[derive(flatipc::Ipc)]
+        #[repr(C)]
+        pub struct Message {
+            pub a: u32,
+            pub c: u64,
+        }
+    }
+
+    let original = original::Message { a: 0, b: 0 }.into_ipc();
+    let documented = documented::new().into_ipc();
+    let renamed = renamed::Message { a: 0, c: 0 }.into_ipc();
+    assert_eq!(original.signature(), documented.signature());
+    assert_ne!(original.signature(), renamed.signature());
+}
+
+#[test]
+fn nested_signatures() {
+    use flatipc::{IntoIpc, Ipc, IpcSafe};
+
+    mod v1 {
+        #[derive(flatipc::IpcSafe, Default)]
+        #[repr(C)]
+        pub struct Rectangle {
+            pub tl: (i16, i16),
+            pub br: (i16, i16),
+        }
+
+        #[derive(flatipc::Ipc, Default)]
+        #[repr(C)]
+        pub struct View {
+            pub rect: Rectangle,
+            pub text: flatipc::String<64>,
+        }
+    }
+
+    mod v2 {
+        use flatipc::String;
+
+        #[derive(flatipc::IpcSafe, Default)]
+        #[repr(C)]
+        pub struct Rectangle {
+            pub tl: (i32, i32),
+            pub br: (i32, i32),
+        }
+
+        #[derive(flatipc::Ipc, Default)]
+        #[repr(C)]
+        pub struct View {
+            pub rect: Rectangle,
+            pub text: String<64>,
+        }
+    }
+
+    assert_ne!(v1::Rectangle::SIGNATURE, v2::Rectangle::SIGNATURE);
+    assert_ne!(v1::View::default().into_ipc().signature(), v2::View::default().into_ipc().signature());
+
+    // The same type spelled differently has the same signature
+    assert_eq!(<flatipc::String<64> as IpcSafe>::SIGNATURE, <crate::String<64> as IpcSafe>::SIGNATURE);
+    assert_ne!(<flatipc::String<64> as IpcSafe>::SIGNATURE, <flatipc::String<65> as IpcSafe>::SIGNATURE);
+    assert_ne!(<Option<u32> as IpcSafe>::SIGNATURE, <Option<i32> as IpcSafe>::SIGNATURE);
+    assert_ne!(<[u8; 4] as IpcSafe>::SIGNATURE, <[u8; 5] as IpcSafe>::SIGNATURE);
+}
+
+#[test]
+fn versioned_signatures() {
+    use flatipc::Ipc;
+
+    mod v1 {
+        #[derive(flatipc::Ipc)]
+        #[repr(C)]
+        pub struct Request {
+            pub value: u32,
+        }
+    }
+
+    mod v2 {
+        #[derive(flatipc::Ipc)]
+        #[flatipc(version = 2)]
+        #[repr(C)]
+        pub struct Request {
+            pub value: u32,
+        }
+    }
+
+    mod pinned {
+        #[derive(flatipc::Ipc)]
+        #[flatipc(signature = 0x5eed_1234)]
+        #[repr(C)]
+        pub struct Request {
+            pub value: u64,
+        }
+    }
+
+    assert_ne!(v1::IpcRequest::SIGNATURE, v2::IpcRequest::SIGNATURE);
+    assert_eq!(pinned::IpcRequest::SIGNATURE, 0x5eed_1234);
+
+    let describe = |signature| match signature {
+        v1::IpcRequest::SIGNATURE => "v1",
+        v2::IpcRequest::SIGNATURE => "v2",
+        pinned::IpcRequest::SIGNATURE => "pinned",
+        _ => "unknown",
+    };
+    assert_eq!(describe(v2::IpcRequest::SIGNATURE), "v2");
+    assert_eq!(describe(0x5eed_1234), "pinned");
+    assert_eq!(describe(0), "unknown");
+}
+
+#[test]
+fn decode_errors() {
+    use flatipc::{DecodeError, IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug, PartialEq)]
+    #[repr(C)]
+    struct Counter {
+        value: u32,
+    }
+
+    let mut counter = Counter { value: 7 }.into_ipc();
+    let size = core::mem::size_of::<IpcCounter>();
+    let data = unsafe { core::slice::from_raw_parts_mut(&mut *counter as *mut Counter as *mut u8, size) };
+
+    assert_eq!(
+        IpcCounter::try_from_slice(&data[..size - 1], IpcCounter::SIGNATURE).err(),
+        Some(DecodeError::TooShort { needed: size, got: size - 1 })
+    );
+    assert_eq!(
+        IpcCounter::try_from_slice(data, IpcCounter::SIGNATURE ^ 1).err(),
+        Some(DecodeError::SignatureMismatch {
+            expected: IpcCounter::SIGNATURE,
+            got: IpcCounter::SIGNATURE ^ 1
+        })
+    );
+    assert!(IpcCounter::from_slice(data, IpcCounter::SIGNATURE ^ 1).is_none());
+    IpcCounter::try_from_slice_mut(data, IpcCounter::SIGNATURE).unwrap().value += 1;
+    assert_eq!(counter.value, 8);
+
+    #[cfg(feature = "xous")]
+    {
+        let range = unsafe { xous::MemoryRange::new(&mut *counter as *mut Counter as usize, size) }.unwrap();
+        let mut msg = xous::MemoryMessage {
+            id: 0,
+            buf: range,
+            offset: xous::MemoryAddress::new(IpcCounter::SIGNATURE ^ 1),
+            valid: None,
+        };
+        assert!(matches!(
+            IpcCounter::try_from_memory_message(&msg),
+            Err(DecodeError::SignatureMismatch { .. })
+        ));
+        msg.offset = xous::MemoryAddress::new(IpcCounter::SIGNATURE);
+        IpcCounter::try_from_memory_message_mut(&mut msg).unwrap().value += 1;
+        assert_eq!(counter.value, 9);
+    }
+}
+
+#[test]
+fn alignment_checks() {
+    use flatipc::{AlignedBuffer, DecodeError, IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug, PartialEq)]
+    #[repr(C)]
+    struct Aligned {
+        value: u64,
+    }
+
+    let original = Aligned { value: 0x1234 }.into_ipc();
+    let size = core::mem::size_of::<IpcAligned>();
+    let bytes = unsafe { core::slice::from_raw_parts(&*original as *const Aligned as *const u8, size) };
+
+    // Copy the message to a location that is not page-aligned
+    let mut unaligned = AlignedBuffer::new(size + 8);
+    unaligned[8..].copy_from_slice(bytes);
+    assert_eq!(
+        IpcAligned::try_from_slice(&unaligned[8..], IpcAligned::SIGNATURE).err(),
+        Some(DecodeError::Misaligned)
+    );
+    assert_eq!(
+        IpcAligned::try_from_slice_mut(&mut unaligned[8..], IpcAligned::SIGNATURE).err(),
+        Some(DecodeError::Misaligned)
+    );
+
+    // Copying into a scratch buffer restores the alignment
+    let scratch = AlignedBuffer::copy_from(&unaligned[8..]);
+    assert_eq!(IpcAligned::try_from_slice(&scratch, IpcAligned::SIGNATURE).unwrap().value, 0x1234);
+
+    // The mock backend always delivers page-aligned buffers to the server
+    let server = flatipc::backend::mock::Server::new(
+        Box::new(|_opcode, signature, _b, buffer| {
+            assert_eq!(buffer.as_ptr() as usize % 4096, 0);
+            assert_eq!(IpcAligned::try_from_slice(buffer, signature).unwrap().value, 0x1234);
+            (0, 0)
+        }),
+        Box::new(|_opcode, signature, _b, buffer| {
+            assert_eq!(buffer.as_ptr() as usize % 4096, 0);
+            IpcAligned::try_from_slice_mut(buffer, signature).unwrap().value += 1;
+            (0, 0)
+        }),
+    );
+    let connection = flatipc::backend::mock::IPC_MACHINE.lock().unwrap().add_server(server);
+    let mock = flatipc::backend::mock::Mock;
+    flatipc::Transport::lend(&mock, connection, 0, IpcAligned::SIGNATURE, &unaligned[8..]).unwrap();
+    flatipc::Transport::lend_mut(&mock, connection, 0, IpcAligned::SIGNATURE, &mut unaligned[8..]).unwrap();
+    assert_eq!(
+        IpcAligned::try_from_slice(&AlignedBuffer::copy_from(&unaligned[8..]), IpcAligned::SIGNATURE)
+            .unwrap()
+            .value,
+        0x1235
+    );
+}
+
+#[test]
+fn validate_received_values() {
+    use flatipc::{DecodeError, IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug, Default)]
+    #[repr(C)]
+    struct Styled {
+        style: GlyphStyle,
+        bounds: Rectangle,
+        visible: bool,
+        initial: char,
+        text: flatipc::String<16>,
+        points: flatipc::Vec<Point, 4>,
+    }
+
+    use core::fmt::Write;
+
+    let mut styled = Styled::default().into_ipc();
+    write!(&mut styled.text, "héllo").unwrap();
+    styled.points.push(Point { x: 1, y: 2 });
+
+    let size = core::mem::size_of::<IpcStyled>();
+    let base = &*styled as *const Styled as *const u8;
+    let original = unsafe { core::slice::from_raw_parts(base, size) };
+    let signature = IpcStyled::SIGNATURE;
+    assert!(IpcStyled::from_slice_checked(original, signature).is_ok());
+
+    // Corrupt one field at a time and make sure the corruption is detected
+    let corrupt = |offset: usize, bytes: &[u8]| {
+        let mut buffer = flatipc::AlignedBuffer::copy_from(original);
+        buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+        IpcStyled::from_slice_mut_checked(&mut buffer, signature).err()
+    };
+    let invalid = |field| Some(DecodeError::InvalidValue { field });
+    assert_eq!(corrupt(core::mem::offset_of!(Styled, style), &[8]), invalid("Styled.style"));
+    assert_eq!(corrupt(core::mem::offset_of!(Styled, visible), &[2]), invalid("Styled.visible"));
+    assert_eq!(
+        corrupt(core::mem::offset_of!(Styled, initial), &0xd800u32.to_ne_bytes()),
+        invalid("Styled.initial")
+    );
+    // Truncate the two-byte "é" so that the string is no longer valid UTF-8
+    let text = original.windows(6).position(|w| w == "héllo".as_bytes()).unwrap();
+    assert_eq!(corrupt(text + 2, b"!"), invalid("Styled.text"));
+
+    // The mismatch and length checks still apply
+    assert!(matches!(
+        IpcStyled::from_slice_checked(original, signature ^ 1),
+        Err(DecodeError::SignatureMismatch { .. })
+    ));
+}
+
+#[test]
+fn unvalidated_fields() {
+    use flatipc::{IntoIpc, Ipc};
+
+    // Implemented by hand, so there is no `Validate`
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    #[repr(C)]
+    struct Handle(u32);
+    unsafe impl flatipc::IpcSafe for Handle {}
+
+    #[derive(Copy, Clone, Debug, flatipc::IpcSafe)]
+    struct Reply {
+        status: Result<u32, u16>,
+        handle: Handle,
+    }
+
+    // `Result`, `Handle` and `TextBounds` can't be validated, which only leaves
+    // `from_slice_checked()` unavailable
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Outcome {
+        status: Result<u32, u16>,
+        handle: Handle,
+        reply: Reply,
+        bounds: TextBounds,
+    }
+
+    let reply = Reply { status: Ok(5), handle: Handle(6) };
+    let outcome = Outcome { status: Err(7), handle: Handle(8), reply, bounds: TextBounds::default() };
+    let outcome = outcome.into_ipc();
+    let size = core::mem::size_of::<IpcOutcome>();
+    let bytes = unsafe { core::slice::from_raw_parts(&outcome as *const IpcOutcome as *const u8, size) };
+    let decoded = IpcOutcome::from_slice(bytes, outcome.signature()).unwrap();
+    assert_eq!(decoded.status, Err(7));
+    assert_eq!(decoded.handle, Handle(8));
+    assert_eq!(decoded.reply.status, Ok(5));
+    assert_eq!(decoded.reply.handle, Handle(6));
+}
+
+#[test]
+fn generic_types() {
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::IpcSafe, Copy, Clone, Debug, Default, PartialEq)]
+    #[repr(C)]
+    struct Pair<T> {
+        first: T,
+        second: T,
+    }
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Response<T, const N: usize> {
+        value: T,
+        items: [u32; N],
+    }
+
+    let mut response = Response { value: Pair { first: 1u16, second: 2u16 }, items: [3u32; 4] }.into_ipc();
+    assert_eq!(core::mem::size_of_val(&response) % 4096, 0);
+    response.items[1] = 5;
+
+    let signature = IpcResponse::<Pair<u16>, 4>::SIGNATURE;
+    assert_ne!(signature, IpcResponse::<Pair<u32>, 4>::SIGNATURE);
+    assert_ne!(signature, IpcResponse::<Pair<u16>, 5>::SIGNATURE);
+    assert_ne!(<Pair<u16> as flatipc::IpcSafe>::SIGNATURE, <Pair<i16> as flatipc::IpcSafe>::SIGNATURE);
+
+    // Generic types have no padding field, so the server must still see zeroes after the original
+    fn check(buffer: &[u8], signature: usize) -> (usize, usize) {
+        let decoded = IpcResponse::<Pair<u16>, 4>::from_slice_checked(buffer, signature).unwrap();
+        assert_eq!(decoded.value, Pair { first: 1, second: 2 });
+        assert!(IpcResponse::<Pair<u32>, 4>::try_from_slice(buffer, signature).is_err());
+        let padding = &buffer[core::mem::size_of::<Response<Pair<u16>, 4>>()..];
+        (decoded.items[1] as usize, padding.iter().all(|&byte| byte == 0) as usize)
+    }
+    let machine = flatipc::backend::mock::MockMachine::new();
+    let server = flatipc::backend::mock::Server::new(
+        Box::new(|_opcode, signature, _b, buffer| check(buffer, signature)),
+        Box::new(|_opcode, signature, _b, buffer| check(buffer, signature)),
+    );
+    let server = machine.lock().unwrap().add_server(server);
+    let result = response.lend_with(&machine, server, 0usize).unwrap();
+    assert_eq!((result.offset, result.valid), (5, 1));
+    let result = response.lend_mut_with(&machine, server, 0usize).unwrap();
+    assert_eq!((result.offset, result.valid), (5, 1));
+}
+
+#[test]
+fn boxed_ipc() {
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Large {
+        header: u32,
+        text: flatipc::String<10000>,
+    }
+
+    let mut large = Large { header: 7, text: flatipc::String::new() }.into_ipc_boxed();
+    assert_eq!(large.as_ptr() as usize % 4096, 0);
+    assert_eq!(core::mem::size_of_val(&*large) % 4096, 0);
+    core::fmt::Write::write_str(&mut large.text, "hello").unwrap();
+
+    let size = core::mem::size_of::<IpcLarge>();
+    let bytes = unsafe { core::slice::from_raw_parts(large.as_ptr() as *const u8, size) };
+    let decoded = IpcLarge::from_slice_checked(bytes, IpcLarge::SIGNATURE).unwrap();
+    assert_eq!(decoded.header, 7);
+    assert_eq!(decoded.text.as_ref(), "hello");
+    // The padding after the original object is zeroed
+    assert!(bytes[core::mem::size_of::<Large>()..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn construct_in_place() {
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug, Default)]
+    #[repr(C)]
+    struct TextView {
+        id: u32,
+        cursor: (u16, u16),
+        text: flatipc::String<3000>,
+    }
+
+    let view = IpcTextView::default_in_place();
+    assert_eq!(view.as_ptr() as usize % 4096, 0);
+    assert_eq!(view.id, 0);
+    assert_eq!(view.text.as_ref(), "");
+
+    // Fill in one field at a time. `text` is left zeroed, which is an empty string.
+    let view = unsafe {
+        IpcTextView::new_with(|view| {
+            let view = view.as_mut_ptr();
+            core::ptr::addr_of_mut!((*view).id).write(9);
+            core::ptr::addr_of_mut!((*view).cursor).write((3, 4));
+        })
+    };
+    assert_eq!(view.id, 9);
+    assert_eq!(view.cursor, (3, 4));
+    assert_eq!(view.text.as_ref(), "");
+
+    let size = core::mem::size_of::<IpcTextView>();
+    let bytes = unsafe { core::slice::from_raw_parts(view.as_ptr() as *const u8, size) };
+    assert!(IpcTextView::from_slice_checked(bytes, IpcTextView::SIGNATURE).is_ok());
+    let boxed = TextView { id: 9, cursor: (3, 4), text: flatipc::String::new() }.into_ipc_boxed();
+    let boxed_bytes = unsafe { core::slice::from_raw_parts(boxed.as_ptr() as *const u8, size) };
+    assert_eq!(bytes[..8], boxed_bytes[..8]);
+}
+
+#[test]
+fn default_in_place_beyond_stack() {
+    use flatipc::Ipc;
+
+    // Far larger than the 2 MiB stack that spawned threads get by default
+    #[derive(flatipc::Ipc)]
+    #[repr(C)]
+    struct Samples {
+        count: u32,
+        samples: [u64; 1 << 20],
+    }
+
+    let samples = std::thread::spawn(IpcSamples::default_in_place).join().unwrap();
+    assert_eq!(samples.count, 0);
+    assert!(samples.samples.iter().all(|&sample| sample == 0));
+}
+
+#[test]
+fn custom_page_size() {
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[flatipc(page_size = 16384)]
+    #[repr(C)]
+    struct Large {
+        value: u32,
+    }
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Small {
+        value: u32,
+    }
+
+    assert_eq!(core::mem::align_of::<IpcLarge>(), 16384);
+    assert_eq!(core::mem::size_of::<IpcLarge>(), 16384);
+    assert_eq!(core::mem::align_of::<IpcSmall>(), flatipc::PAGE_SIZE);
+    assert_eq!(core::mem::size_of::<IpcSmall>(), flatipc::PAGE_SIZE);
+
+    let large = Large { value: 5 }.into_ipc_boxed();
+    assert_eq!(large.as_ptr() as usize % 16384, 0);
+
+    // A buffer that is only aligned to 4096 bytes is rejected
+    let mut buffer = flatipc::AlignedBuffer::with_alignment(16384 + 4096, 16384);
+    assert_eq!(
+        IpcLarge::try_from_slice(&buffer[4096..], IpcLarge::SIGNATURE).err(),
+        Some(flatipc::DecodeError::Misaligned)
+    );
+    let size = core::mem::size_of::<IpcLarge>();
+    buffer[..size].copy_from_slice(unsafe { core::slice::from_raw_parts(large.as_ptr() as *const u8, size) });
+    assert_eq!(IpcLarge::from_slice(&buffer[..size], IpcLarge::SIGNATURE).unwrap().value, 5);
+
+    // The mock preserves the alignment of the buffer when lending it
+    let server = flatipc::backend::mock::Server::new(
+        Box::new(|_opcode, signature, _b, buffer| {
+            assert_eq!(IpcLarge::from_slice(buffer, signature).unwrap().value, 5);
+            (0, 0)
+        }),
+        Box::new(|_opcode, _a, _b, _buffer| (0, 0)),
+    );
+    let connection = flatipc::backend::mock::IPC_MACHINE.lock().unwrap().add_server(server);
+    large.lend_with(&flatipc::backend::mock::Mock, connection, 0usize).unwrap();
+}
+
+#[test]
+fn lend_results() {
+    use flatipc::backend::mock::{IPC_MACHINE, Mock, Server};
+    use flatipc::{IntoIpc, Ipc, LendResult};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Request {
+        text: [u8; 32],
+        length: usize,
+    }
+
+    // Echo the signature back as the status code, and report how many bytes were filled in
+    let server = Server::new(
+        Box::new(|opcode, signature, _b, _buffer| (signature, opcode)),
+        Box::new(|_opcode, signature, _b, buffer| {
+            let request = IpcRequest::from_slice_mut(buffer, signature).unwrap();
+            request.text[..5].copy_from_slice(b"hello");
+            request.length = 5;
+            (0, 5)
+        }),
+    );
+    let connection = IPC_MACHINE.lock().unwrap().add_server(server);
+
+    let mut request = Request { text: [0; 32], length: 0 }.into_ipc();
+    let result = request.lend_with(&Mock, connection, 3usize).unwrap();
+    assert_eq!(result, LendResult { offset: IpcRequest::SIGNATURE, valid: 3 });
+    let result = request.lend_mut_with(&Mock, connection, 4usize).unwrap();
+    assert_eq!(result, LendResult { offset: 0, valid: 5 });
+    assert_eq!(&request.text[..result.valid], b"hello");
+}
+
+#[test]
+fn busy_server() {
+    use flatipc::backend::mock::{IPC_MACHINE, MessageKind, Mock};
+    use flatipc::{IntoIpc, Ipc, Transport};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Counter(u32);
+
+    let handle = IPC_MACHINE.lock().unwrap().create_server();
+    handle.set_queue_depth(2);
+    let connection = IPC_MACHINE.lock().unwrap().connect(handle.sid()).unwrap();
+
+    // Nobody is receiving, so messages wait in the queue until it is full
+    // Without the `xous` feature, the mock's error is also `flatipc::Error`
+    #[allow(clippy::useless_conversion)]
+    let busy = || Some(flatipc::Error::from(flatipc::backend::mock::Error::ServerBusy));
+    Mock.send(connection, 0, [1, 0, 0, 0]).unwrap();
+    Mock.send(connection, 0, [2, 0, 0, 0]).unwrap();
+    assert_eq!(handle.queued(), 2);
+    let mut counter = Counter(0).into_ipc();
+    assert_eq!(counter.try_lend_with(&Mock, connection, 0usize).err(), busy());
+    assert_eq!(counter.try_lend_mut_with(&Mock, connection, 0usize).err(), busy());
+
+    // `send` doesn't wait for a reply, but like the kernel it waits for space in the queue
+    let sender = std::thread::spawn(move || Mock.send(connection, 0, [3, 0, 0, 0]).unwrap());
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert!(!sender.is_finished());
+    assert_eq!(handle.receive().unwrap().args[0], 1);
+    sender.join().unwrap();
+    assert_eq!(handle.queued(), 2);
+
+    // Blocking calls wait for space in the queue, and then for the server to reply
+    let client = std::thread::spawn(move || {
+        let result = counter.lend_mut_with(&Mock, connection, 0usize).unwrap();
+        (counter.0, result.offset)
+    });
+    for expected in [2, 3] {
+        let envelope = handle.receive().unwrap();
+        assert_eq!((envelope.kind, envelope.args[0]), (MessageKind::Scalar, expected));
+    }
+    let mut envelope = handle.receive().unwrap();
+    assert_eq!(envelope.kind, MessageKind::MutableBorrow);
+    let signature = envelope.args[0];
+    IpcCounter::from_slice_mut(envelope.buffer_mut().unwrap(), signature).unwrap().0 += 1;
+    envelope.reply(7, 0);
+    assert_eq!(client.join().unwrap(), (1, 7));
+
+    // `try_` calls succeed when there's space, and dropping the envelope returns it
+    let client =
+        std::thread::spawn(move || Counter(0).into_ipc().try_lend_with(&Mock, connection, 0usize).unwrap());
+    let envelope = handle.receive().unwrap();
+    assert_eq!(envelope.kind, MessageKind::Borrow);
+    assert!(envelope.buffer().is_some());
+    drop(envelope);
+    assert_eq!(client.join().unwrap(), flatipc::LendResult::default());
+    assert!(handle.try_receive().unwrap().is_none());
+}
+
+#[test]
+fn nested_servers() {
+    use flatipc::backend::mock::{IPC_MACHINE, Mock, Server};
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    // The inner server doubles the value it's lent
+    let inner = Server::new(
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+        Box::new(|_opcode, signature, _b, buffer| {
+            IpcValue::from_slice_mut(buffer, signature).unwrap().0 *= 2;
+            (0, 0)
+        }),
+    );
+    let inner = IPC_MACHINE.lock().unwrap().add_server(inner);
+
+    // The outer server adds one and then asks the inner server to double it, which
+    // would deadlock if the machine were locked while delivering messages.
+    let outer = Server::new(
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+        Box::new(move |_opcode, signature, _b, buffer| {
+            let value = IpcValue::from_slice_mut(buffer, signature).unwrap();
+            value.0 += 1;
+            value.lend_mut_with(&Mock, inner, 0usize).unwrap();
+            (0, 0)
+        }),
+    );
+    let outer = IPC_MACHINE.lock().unwrap().add_server(outer);
+
+    // Several clients may talk to the same servers at once
+    let clients: std::vec::Vec<_> = (0..8)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let mut value = Value(i).into_ipc();
+                value.lend_mut_with(&Mock, outer, 0usize).unwrap();
+                value.0
+            })
+        })
+        .collect();
+    for (i, client) in clients.into_iter().enumerate() {
+        assert_eq!(client.join().unwrap(), (i as u32 + 1) * 2);
+    }
+}
+
+#[test]
+fn mock_errors() {
+    use flatipc::backend::mock::{Error, IPC_MACHINE, Mock, Server};
+    use flatipc::{IntoIpc, Ipc, Transport};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Small(u32);
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Large([u8; 2 * flatipc::PAGE_SIZE]);
+
+    let server = Server::new(
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+    )
+    .with_max_message_size(flatipc::PAGE_SIZE);
+    let connection = IPC_MACHINE.lock().unwrap().add_server(server);
+
+    // Without the `xous` feature, the mock's error is also `flatipc::Error`
+    #[allow(clippy::useless_conversion)]
+    let error = |error: Error| Some(flatipc::Error::from(error));
+    let mut small = Small(1).into_ipc();
+    let large = Large([0; 2 * flatipc::PAGE_SIZE]).into_ipc_boxed();
+    assert!(small.lend_with(&Mock, connection, 0usize).is_ok());
+    assert_eq!(large.lend_with(&Mock, connection, 0usize).err(), error(Error::MessageTooLarge));
+    assert_eq!(large.try_lend_with(&Mock, connection, 0usize).err(), error(Error::MessageTooLarge));
+
+    // Messages to a server that doesn't exist fail rather than panicking
+    let missing = connection + 1000;
+    assert_eq!(small.lend_with(&Mock, missing, 0usize).err(), error(Error::ServerNotFound));
+    assert_eq!(small.lend_mut_with(&Mock, missing, 0usize).err(), error(Error::ServerNotFound));
+    assert_eq!(Mock.scalar(missing, 0, [0; 4]).err(), error(Error::ServerNotFound));
+
+    IPC_MACHINE.lock().unwrap().terminate(connection).unwrap();
+    assert_eq!(small.lend_mut_with(&Mock, connection, 0usize).err(), error(Error::ProcessTerminated));
+    assert_eq!(Mock.send(connection, 0, [0; 4]).err(), error(Error::ProcessTerminated));
+}
+
+#[test]
+fn mock_service_discovery() {
+    use flatipc::backend::mock::{Error, IPC_MACHINE, Mock, Server};
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Ping(u32);
+
+    let server = || {
+        Server::new(
+            Box::new(|_opcode, _signature, _b, _buffer| (1, 0)),
+            Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+        )
+    };
+
+    let mut machine = IPC_MACHINE.lock().unwrap();
+    let sid = *b"discovery-server";
+    server().spawn(machine.create_server_with_sid(sid).unwrap());
+    assert_eq!(machine.create_server_with_sid(sid).err().unwrap(), Error::ServerExists);
+    let handle = machine.create_server_with_name("_Discovery test_").unwrap();
+    let named = handle.sid();
+    server().spawn(handle);
+    assert_eq!(machine.connect(*b"no-such-server!!").err(), Some(Error::ServerNotFound));
+    assert_eq!(machine.connect_by_name("_No such server_").err(), Some(Error::ServerNotFound));
+
+    let by_sid = machine.connect(sid).unwrap();
+    let second = machine.connect(sid).unwrap();
+    let by_name = machine.connect_by_name("_Discovery test_").unwrap();
+    assert_eq!(machine.connect(named).unwrap(), by_name + 1);
+    drop(machine);
+
+    #[allow(clippy::useless_conversion)]
+    let error = |error: Error| Some(flatipc::Error::from(error));
+    let ping = Ping(1).into_ipc();
+    assert_eq!(ping.lend_with(&Mock, by_sid, 0usize).unwrap().offset, 1);
+    assert_eq!(ping.lend_with(&Mock, by_name, 0usize).unwrap().offset, 1);
+
+    // Closing one connection leaves the others open
+    IPC_MACHINE.lock().unwrap().disconnect(by_sid).unwrap();
+    assert_eq!(ping.lend_with(&Mock, by_sid, 0usize).err(), error(Error::ServerNotFound));
+    assert_eq!(IPC_MACHINE.lock().unwrap().disconnect(by_sid).err(), Some(Error::ServerNotFound));
+    assert!(ping.lend_with(&Mock, second, 0usize).is_ok());
+
+    // Destroying the server breaks existing connections and frees the SID and name
+    let mut machine = IPC_MACHINE.lock().unwrap();
+    machine.destroy_server(sid).unwrap();
+    machine.destroy_server(named).unwrap();
+    assert_eq!(machine.connect(sid).err(), Some(Error::ServerNotFound));
+    assert_eq!(machine.connect_by_name("_Discovery test_").err(), Some(Error::ServerNotFound));
+    let replacement = machine.create_server_with_sid(sid).unwrap();
+    drop(machine);
+    assert_eq!(ping.lend_with(&Mock, second, 0usize).err(), error(Error::ServerNotFound));
+    assert_eq!(ping.lend_with(&Mock, by_name, 0usize).err(), error(Error::ServerNotFound));
+    IPC_MACHINE.lock().unwrap().destroy_server(sid).unwrap();
+    assert!(replacement.receive().is_err());
+}
+
+#[test]
+fn isolated_machines() {
+    use flatipc::backend::mock::{Error, Mock, MockMachine, Server};
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    // Each server adds its own amount to the value it's lent
+    let adder = |amount: u32| {
+        Server::new(
+            Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+            Box::new(move |_opcode, signature, _b, buffer| {
+                IpcValue::from_slice_mut(buffer, signature).unwrap().0 += amount;
+                (0, 0)
+            }),
+        )
+    };
+
+    // Both machines hand out the same connection IDs without interfering
+    let first = MockMachine::new();
+    let second = MockMachine::new();
+    let one = first.lock().unwrap().add_server(adder(1));
+    let ten = second.lock().unwrap().add_server(adder(10));
+    assert_eq!(one, ten);
+
+    let mut value = Value(0).into_ipc();
+    value.lend_mut_with(&first, one, 0usize).unwrap();
+    value.lend_mut_with(&second, ten, 0usize).unwrap();
+    assert_eq!(value.0, 11);
+
+    // `Mock` uses whichever machine the current thread has entered, and servers
+    // keep using their own machine when they send messages of their own
+    let relay = Server::new(
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+        Box::new(move |_opcode, signature, _b, buffer| {
+            IpcValue::from_slice_mut(buffer, signature).unwrap().lend_mut_with(&Mock, one, 0usize).unwrap();
+            (0, 0)
+        }),
+    );
+    let relay = first.lock().unwrap().add_server(relay);
+    {
+        let _machine = first.enter();
+        value.lend_mut_with(&Mock, relay, 0usize).unwrap();
+        assert_eq!(value.0, 12);
+        let _machine = second.enter();
+        value.lend_mut_with(&Mock, ten, 0usize).unwrap();
+        assert_eq!(value.0, 22);
+    }
+
+    // Dropping a machine stops its servers
+    let stale = first.clone();
+    drop(first);
+    value.lend_mut_with(&stale, one, 0usize).unwrap();
+    assert_eq!(value.0, 23);
+    let _machine = stale.enter();
+    drop(stale);
+    #[allow(clippy::useless_conversion)]
+    let error = |error: Error| Some(flatipc::Error::from(error));
+    assert_eq!(value.lend_mut_with(&Mock, one, 0usize).err(), error(Error::ServerNotFound));
+}
+
+#[test]
+fn recorded_messages() {
+    use flatipc::backend::mock::{Error, MessageKind, MockMachine, Record, Server};
+    use flatipc::{IntoIpc, Ipc, Transport};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    let machine = MockMachine::new();
+    let server = Server::new(
+        Box::new(|_opcode, _signature, _b, _buffer| (1, 0)),
+        Box::new(|_opcode, signature, _b, buffer| {
+            IpcValue::from_slice_mut(buffer, signature).unwrap().0 += 1;
+            (2, 0)
+        }),
+    )
+    .with_scalar(Box::new(|opcode, args| (opcode + args[0], 0)));
+    let connection = machine.lock().unwrap().add_server(server);
+
+    // Messages sent before recording starts aren't recorded
+    let mut value = Value(1).into_ipc();
+    value.lend_mut_with(&machine, connection, 5usize).unwrap();
+    let recorder = machine.lock().unwrap().start_recording();
+
+    value.lend_mut_with(&machine, connection, 6usize).unwrap();
+    value.lend_with(&machine, connection, 7usize).unwrap();
+    assert_eq!(machine.scalar(connection, 8, [1, 2, 3, 4]).unwrap(), (9, 0));
+    assert!(value.lend_with(&machine, connection + 1, 9usize).is_err());
+    machine.lock().unwrap().stop_recording();
+    machine.scalar(connection, 10, [0; 4]).unwrap();
+
+    let records = recorder.take();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].connection, connection);
+    assert_eq!(records[0].opcode, 6);
+    assert_eq!(records[0].kind, MessageKind::MutableBorrow);
+    assert_eq!(records[0].signature(), Some(IpcValue::SIGNATURE));
+    assert_eq!(records[0].before.as_ref().unwrap()[..4], 2u32.to_ne_bytes());
+    assert_eq!(records[0].after.as_ref().unwrap()[..4], 3u32.to_ne_bytes());
+    assert_eq!(records[0].result, Ok((2, 0)));
+
+    assert_eq!(records[1].kind, MessageKind::Borrow);
+    assert_eq!(records[1].before, records[1].after);
+    assert_eq!(records[1].result, Ok((1, 0)));
+
+    assert_eq!(
+        records[2],
+        Record {
+            time: records[2].time,
+            connection,
+            opcode: 8,
+            kind: MessageKind::BlockingScalar,
+            args: [1, 2, 3, 4],
+            before: None,
+            after: None,
+            result: Ok((9, 0)),
+        }
+    );
+    assert_eq!(records[2].signature(), None);
+
+    assert_eq!(records[3].connection, connection + 1);
+    assert_eq!(records[3].result, Err(Error::ServerNotFound));
+    assert!(recorder.records().is_empty());
+}
+
+#[test]
+fn replay_capture() {
+    use flatipc::backend::mock::{MockMachine, Server};
+    use flatipc::replay::{Capture, Mismatch};
+    use flatipc::{IntoIpc, Ipc, Transport};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    // A server that adds `step` to every value it's lent, and reports the new value
+    let adder = |step: u32| {
+        Server::new(
+            Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+            Box::new(move |_opcode, signature, _b, buffer| {
+                let value = IpcValue::from_slice_mut(buffer, signature).unwrap();
+                value.0 += step;
+                (value.0 as usize, 0)
+            }),
+        )
+        .with_scalar(Box::new(|opcode, args| (opcode + args[0], 0)))
+    };
+
+    let machine = MockMachine::new();
+    let connection = machine.lock().unwrap().add_server(adder(1));
+    let recorder = machine.lock().unwrap().start_recording();
+    let mut value = Value(1).into_ipc();
+    value.lend_mut_with(&machine, connection, 1usize).unwrap();
+    value.lend_mut_with(&machine, connection, 1usize).unwrap();
+    machine.scalar(connection, 2, [3, 0, 0, 0]).unwrap();
+    machine.send(connection, 4, [0; 4]).unwrap();
+
+    // The capture survives being written to a file and read back
+    let capture = recorder.capture();
+    assert_eq!(capture.messages.len(), 4);
+    assert_eq!(capture.messages[1].signature(), Some(IpcValue::SIGNATURE));
+    assert_eq!(capture.messages[1].result, Some((3, 0)));
+    let mut file = std::vec::Vec::new();
+    capture.write_to(&mut file).unwrap();
+    assert_eq!(file[..4], flatipc::replay::MAGIC);
+    let read = Capture::read_from(&file[..]).unwrap();
+    assert_eq!(read, capture);
+    assert!(Capture::read_from(&file[..file.len() - 1]).is_err());
+
+    // A fresh server behaves the same, even in a different machine
+    let replayer = MockMachine::new();
+    let same = replayer.lock().unwrap().add_server(adder(1));
+    read.replay(&replayer, same).unwrap();
+
+    // A server with a bug is caught on the first message that differs
+    let buggy = replayer.lock().unwrap().add_server(adder(2));
+    assert!(matches!(read.replay(&replayer, buggy), Err(Mismatch::Result { index: 0, .. })));
+}
+
+#[test]
+fn moved_messages() {
+    use flatipc::backend::mock::{MessageKind, MockMachine};
+    use flatipc::{DecodeError, IntoIpc, Ipc, Transport};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    let machine = MockMachine::new();
+    let handle = machine.lock().unwrap().create_server();
+    let connection = machine.lock().unwrap().connect(handle.sid()).unwrap();
+    let recorder = machine.lock().unwrap().start_recording();
+
+    // Sending doesn't wait for the server, so both messages are queued before
+    // either is received
+    Value(7).into_ipc_boxed().send_with(&machine, connection, 1usize).unwrap();
+    Value(8).into_ipc().send_with(&machine, connection, 2usize).unwrap();
+    assert_eq!(handle.queued(), 2);
+
+    // The server takes over the buffer, and may keep it after the envelope is gone
+    let envelope = handle.receive().unwrap();
+    assert_eq!((envelope.opcode, envelope.kind), (1, MessageKind::Move));
+    let first = IpcValue::from_owned_message(envelope).unwrap();
+    let mut second = handle.receive().unwrap();
+    let second =
+        flatipc::IpcBox::<IpcValue>::from_buffer(second.take_buffer().unwrap(), second.args[0]).unwrap();
+    assert_eq!((first.0, second.0), (7, 8));
+
+    // Only moved buffers can be taken over
+    machine.send(connection, 3, [0; 4]).unwrap();
+    let scalar = handle.receive().unwrap();
+    assert_eq!(IpcValue::from_owned_message(scalar).err(), Some(DecodeError::NotMoved));
+
+    let records = recorder.take();
+    assert_eq!(records[0].kind, MessageKind::Move);
+    assert_eq!(records[0].signature(), Some(IpcValue::SIGNATURE));
+    assert_eq!(records[0].before.as_ref().unwrap()[..4], 7u32.to_ne_bytes());
+    assert_eq!(records[0].after, None);
+}
+
+#[test]
+fn scalar_messages() {
+    use flatipc::backend::mock::{MockMachine, Server};
+    use flatipc::{DecodeError, IntoIpc, Ipc, IpcScalar};
+
+    #[derive(flatipc::IpcSafe, flatipc::IpcScalar, Debug, PartialEq)]
+    #[repr(C)]
+    struct Point {
+        x: u32,
+        y: u32,
+        visible: bool,
+        layer: u8,
+        depth: u16,
+    }
+
+    #[derive(flatipc::IpcSafe, flatipc::IpcScalar, Debug, PartialEq)]
+    #[repr(u8)]
+    enum Mode {
+        Off = 0,
+        On = 1,
+    }
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    let point = Point { x: 1, y: 2, visible: true, layer: 3, depth: 4 };
+    assert_eq!(
+        Point::from_args(point.to_args()),
+        Ok(Point { x: 1, y: 2, visible: true, layer: 3, depth: 4 })
+    );
+    assert_eq!(Mode::from_args(Mode::On.to_args()), Ok(Mode::On));
+    assert_eq!(Mode::from_args([2, 0, 0, 0]).err(), Some(DecodeError::InvalidValue { field: "" }));
+
+    // A blocking scalar is answered by the server without lending any memory
+    let machine = MockMachine::new();
+    let server = Server::new(
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+        Box::new(|_opcode, _signature, _b, _buffer| (0, 0)),
+    )
+    .with_scalar(Box::new(|_opcode, args| match Point::from_args(args) {
+        Ok(point) => ((point.x + point.y) as usize, point.depth as usize),
+        Err(_) => (usize::MAX, 0),
+    }));
+    let adder = machine.lock().unwrap().add_server(server);
+    assert_eq!(point.blocking_scalar_with(&machine, adder, 0usize).unwrap(), (3, 4));
+
+    // Non-blocking scalars are decoded from the envelope
+    let handle = machine.lock().unwrap().create_server();
+    let connection = machine.lock().unwrap().connect(handle.sid()).unwrap();
+    Mode::Off.send_scalar_with(&machine, connection, 1usize).unwrap();
+    Value(0).into_ipc().send_with(&machine, connection, 2usize).unwrap();
+    assert_eq!(Mode::from_scalar_message(&handle.receive().unwrap()), Ok(Mode::Off));
+    assert_eq!(Mode::from_scalar_message(&handle.receive().unwrap()), Err(DecodeError::NotScalar));
+}
+
+#[test]
+fn service_macro() {
+    use flatipc::IntoIpc;
+    use flatipc::backend::mock::MockMachine;
+    use flatipc::service::ServiceError;
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Label {
+        text: [u8; 16],
+        len: u32,
+    }
+
+    #[derive(flatipc::IpcSafe, flatipc::IpcScalar, Copy, Clone, Debug, PartialEq)]
+    #[repr(C)]
+    struct Area {
+        width: u16,
+        height: u16,
+    }
+
+    #[flatipc::service]
+    trait Canvas {
+        /// Upper-case the label in place.
+        fn shout(&mut self, label: &mut Label);
+        fn measure(&self, label: &Label) -> u32;
+        fn clear(&mut self, area: Area) -> u32;
+        fn cleared(&self) -> u32;
+    }
+
+    #[derive(Default)]
+    struct Screen {
+        cleared: u32,
+    }
+
+    impl Canvas for Screen {
+        fn shout(&mut self, label: &mut Label) { label.text.make_ascii_uppercase(); }
+
+        fn measure(&self, label: &Label) -> u32 { label.len }
+
+        fn clear(&mut self, area: Area) -> u32 {
+            self.cleared += area.width as u32 * area.height as u32;
+            self.cleared
+        }
+
+        fn cleared(&self) -> u32 { self.cleared }
+    }
+
+    // Opcodes are hashed from the method names, as with `#[derive(Opcodes)]`
+    #[derive(flatipc::Opcodes)]
+    enum Named {
+        Clear,
+    }
+    assert_eq!(CanvasOpcode::try_from(usize::from(Named::Clear)), Ok(CanvasOpcode::Clear));
+    assert_eq!(CanvasOpcode::try_from(4), Err(4));
+
+    #[flatipc::service]
+    trait Numbered {
+        #[opcode = 1]
+        fn first(&self) -> u32;
+        #[opcode = 0x20]
+        fn second(&self) -> u32;
+    }
+    assert_eq!(usize::from(NumberedOpcode::Second), 0x20);
+
+    let machine = MockMachine::new();
+    let handle = machine.lock().unwrap().create_server();
+    let connection = machine.lock().unwrap().connect(handle.sid()).unwrap();
+    let server = std::thread::spawn(move || {
+        let mut screen = Screen::default();
+        let mut results = vec![];
+        while let Ok(envelope) = handle.receive() {
+            results.push(screen.dispatch(envelope));
+        }
+        results
+    });
+
+    let canvas = CanvasClient::with_transport(machine.clone(), connection);
+    let mut label = Label { text: *b"hello, world\0\0\0\0", len: 12 }.into_ipc();
+    canvas.shout(&mut label).unwrap();
+    assert_eq!(&label.text[..12], b"HELLO, WORLD");
+    assert_eq!(canvas.measure(&label), Ok(12));
+    assert_eq!(canvas.clear(Area { width: 2, height: 3 }), Ok(6));
+    assert_eq!(canvas.clear(Area { width: 1, height: 1 }), Ok(7));
+    assert_eq!(canvas.cleared(), Ok(7));
+
+    // Requests the service doesn't understand are still replied to
+    assert_eq!(flatipc::Transport::scalar(&machine, connection, 9, [0; 4]).unwrap(), (0, 0));
+    // Dropping the last reference to the machine stops the server
+    drop((canvas, machine));
+    let results = server.join().unwrap();
+    assert_eq!(results.len(), 6);
+    assert_eq!(results[5], Err(ServiceError::UnknownOpcode(9)));
+}
+
+#[test]
+fn dispatcher() {
+    use flatipc::backend::mock::MockMachine;
+    use flatipc::service::ServiceError;
+    use flatipc::{DecodeError, Dispatcher, IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Counter(u32);
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Greeting([u8; 8]);
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Unrelated(u64);
+
+    // A client that disagrees about the type of a field, sharing its signature
+    mod server {
+        #[derive(flatipc::Ipc, Debug)]
+        #[repr(C)]
+        #[flatipc(signature = 0xf1a9)]
+        pub struct Flag(pub bool);
+    }
+    mod client {
+        #[derive(flatipc::Ipc, Debug)]
+        #[repr(C)]
+        #[flatipc(signature = 0xf1a9)]
+        pub struct Flag(pub u8);
+    }
+
+    let machine = MockMachine::new();
+    let handle = machine.lock().unwrap().create_server();
+    let connection = machine.lock().unwrap().connect(handle.sid()).unwrap();
+    let server = std::thread::spawn(move || {
+        let mut handled = 0;
+        let mut results = vec![];
+        {
+            let mut dispatcher = Dispatcher::new();
+            dispatcher
+                .handler::<IpcCounter>(1usize, |counter: &mut Counter| {
+                    handled += 1;
+                    counter.0 += 1;
+                    counter.0
+                })
+                .handler::<IpcGreeting>(1usize, |greeting: &mut Greeting| greeting.0.make_ascii_uppercase())
+                .handler::<server::IpcFlag>(3usize, |flag: &mut server::Flag| flag.0 = !flag.0);
+            while let Ok(envelope) = handle.receive() {
+                results.push(dispatcher.dispatch(envelope));
+            }
+        }
+        (handled, results)
+    });
+
+    // Both types share an opcode, and the signature picks the handler
+    let mut counter = Counter(41).into_ipc();
+    assert_eq!(counter.lend_mut_with(&machine, connection, 1usize).unwrap().offset, 42);
+    assert_eq!(counter.0, 42);
+    let mut greeting = Greeting(*b"hello!\0\0").into_ipc();
+    greeting.lend_mut_with(&machine, connection, 1usize).unwrap();
+    assert_eq!(&greeting.0, b"HELLO!\0\0");
+
+    // Anything else is returned untouched
+    let mut unrelated = Unrelated(7).into_ipc();
+    assert_eq!(
+        unrelated.lend_mut_with(&machine, connection, 1usize).unwrap(),
+        flatipc::LendResult::default()
+    );
+    counter.lend_mut_with(&machine, connection, 2usize).unwrap();
+    counter.lend_with(&machine, connection, 1usize).unwrap();
+    assert_eq!((unrelated.0, counter.0), (7, 42));
+
+    // Fields are validated before reaching the handler
+    let mut flag = client::Flag(1).into_ipc();
+    flag.lend_mut_with(&machine, connection, 3usize).unwrap();
+    assert_eq!(flag.0, 0);
+    flag.0 = 2;
+    flag.lend_mut_with(&machine, connection, 3usize).unwrap();
+    assert_eq!(flag.0, 2);
+
+    drop(machine);
+    let (handled, results) = server.join().unwrap();
+    assert_eq!(handled, 1);
+    assert_eq!(results[..2], [Ok(()), Ok(())]);
+    assert!(matches!(results[2], Err(ServiceError::Decode(DecodeError::SignatureMismatch { .. }))));
+    assert_eq!(results[3], Err(ServiceError::UnknownOpcode(2)));
+    assert_eq!(results[4], Err(ServiceError::Decode(DecodeError::NotLent)));
+    assert_eq!(results[5], Ok(()));
+    assert_eq!(results[6], Err(ServiceError::Decode(DecodeError::InvalidValue { field: "Flag.0" })));
+}
+
+#[test]
+fn derived_opcodes() {
+    use flatipc::backend::mock::{MockMachine, Server};
+    use flatipc::{IntoIpc, Ipc};
+
+    #[derive(flatipc::Opcodes, Copy, Clone, Debug, PartialEq)]
+    enum Explicit {
+        Draw = 1,
+        Clear = 0x20,
+    }
+
+    #[derive(flatipc::Opcodes, Copy, Clone, Debug, PartialEq)]
+    enum Hashed {
+        Draw,
+        Clear,
+    }
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Value(u32);
+
+    assert_eq!(usize::from(Explicit::Clear), 0x20);
+    assert_eq!(Explicit::try_from(1), Ok(Explicit::Draw));
+    assert_eq!(Explicit::try_from(2), Err(2));
+
+    // Hashed opcodes depend only on the name of the variant
+    assert_eq!(usize::from(Hashed::Draw), 0x0f6d_1e33);
+    assert_eq!(Hashed::try_from(usize::from(Hashed::Clear)), Ok(Hashed::Clear));
+    assert_eq!(Hashed::try_from(1), Err(1));
+
+    // The server sees the numeric opcode
+    let machine = MockMachine::new();
+    let echo = Server::new(
+        Box::new(|opcode, _signature, _b, _buffer| (opcode, 0)),
+        Box::new(|opcode, _signature, _b, _buffer| (opcode, 0)),
+    );
+    let echo = machine.lock().unwrap().add_server(echo);
+    let mut value = Value(0).into_ipc();
+    assert_eq!(value.lend_with(&machine, echo, Explicit::Clear).unwrap().offset, 0x20);
+    let opcode = value.lend_mut_with(&machine, echo, Hashed::Draw).unwrap().offset;
+    assert_eq!(Hashed::try_from(opcode), Ok(Hashed::Draw));
+    assert_eq!(value.lend_with(&machine, echo, 7usize).unwrap().offset, 7);
+}
+
+#[test]
+fn message_sets() {
+    use flatipc::backend::mock::MockMachine;
+    use flatipc::{DecodeError, IntoIpc, Ipc};
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Label([u8; 8]);
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Area {
+        width: u32,
+        height: u32,
+    }
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Unrelated(u64);
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    struct Switch(bool);
+
+    #[flatipc::message_set]
+    enum Requests {
+        Draw(Label),
+        Clear(Area),
+        Toggle(Switch),
+    }
+
+    // The signature alone picks the type
+    let mut area = Area { width: 2, height: 3 }.into_ipc();
+    let signature = area.signature();
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(&mut area as *mut IpcArea as *mut u8, core::mem::size_of::<IpcArea>())
+    };
+    match Requests::try_from_slice_mut(buffer, signature) {
+        Ok(Requests::Clear(area)) => area.width = 4,
+        _ => panic!("expected an area"),
+    }
+    assert_eq!(area.width, 4);
+    let unrelated = Unrelated(0).into_ipc();
+    assert!(matches!(
+        Requests::try_from_slice_mut(&mut [0u8; 8], unrelated.signature()),
+        Err(DecodeError::SignatureMismatch { .. })
+    ));
+
+    // Invalid values are rejected by the checked decoder
+    let switch = Switch(true).into_ipc();
+    let mut buffer = flatipc::AlignedBuffer::copy_from(unsafe {
+        core::slice::from_raw_parts(
+            &switch as *const IpcSwitch as *const u8,
+            core::mem::size_of::<IpcSwitch>(),
+        )
+    });
+    assert!(matches!(
+        Requests::from_slice_mut_checked(&mut buffer, switch.signature()),
+        Ok(Requests::Toggle(switch)) if switch.0
+    ));
+    buffer[0] = 2;
+    assert!(matches!(
+        Requests::from_slice_mut_checked(&mut buffer, switch.signature()),
+        Err(DecodeError::InvalidValue { field: "Switch.0" })
+    ));
+
+    // Requests are decoded straight from the envelope
+    let machine = MockMachine::new();
+    let handle = machine.lock().unwrap().create_server();
+    let connection = machine.lock().unwrap().connect(handle.sid()).unwrap();
+    let server = std::thread::spawn(move || {
+        while let Ok(mut envelope) = handle.receive() {
+            let result = match Requests::from_request(&mut envelope) {
+                Ok(Requests::Draw(label)) => {
+                    label.0.make_ascii_uppercase();
+                    1
+                }
+                Ok(Requests::Clear(area)) => (area.width * area.height) as usize,
+                Ok(Requests::Toggle(_)) | Err(_) => 0,
+            };
+            envelope.reply(result, 0);
+        }
+    });
+    let mut label = Label(*b"label\0\0\0").into_ipc();
+    assert_eq!(label.lend_mut_with(&machine, connection, 0usize).unwrap().offset, 1);
+    assert_eq!(&label.0, b"LABEL\0\0\0");
+    assert_eq!(area.lend_mut_with(&machine, connection, 0usize).unwrap().offset, 12);
+    assert_eq!(area.lend_with(&machine, connection, 0usize).unwrap().offset, 0);
+    drop(machine);
+    server.join().unwrap();
+}
+
+#[test]
+fn tuple_arrays() {
+    use flatipc::{DecodeError, IntoIpc, Ipc};
+
+    mod v1 {
+        #[derive(flatipc::Ipc, Debug, Default)]
+        #[repr(C)]
+        pub struct Pairs {
+            pub pairs: [(u8, bool); 4],
+        }
+    }
+
+    mod v2 {
+        #[derive(flatipc::Ipc, Debug, Default)]
+        #[repr(C)]
+        pub struct Pairs {
+            pub pairs: [(u8, bool); 5],
+        }
+    }
+
+    mod v3 {
+        #[derive(flatipc::Ipc, Debug, Default)]
+        #[repr(C)]
+        pub struct Pairs {
+            pub pairs: [(u8, u8); 4],
+        }
+    }
+
+    let pairs = v1::Pairs { pairs: [(1, true), (2, false), (3, true), (4, false)] }.into_ipc();
+    assert_ne!(pairs.signature(), v2::Pairs::default().into_ipc().signature());
+    assert_ne!(pairs.signature(), v3::Pairs::default().into_ipc().signature());
+
+    // Every element of the array is validated
+    let mut buffer = flatipc::AlignedBuffer::copy_from(unsafe {
+        core::slice::from_raw_parts(&pairs as *const _ as *const u8, core::mem::size_of_val(&pairs))
+    });
+    let decoded = v1::IpcPairs::from_slice_checked(&buffer, pairs.signature()).unwrap();
+    assert_eq!(decoded.pairs[2], (3, true));
+    buffer[7] = 2;
+    assert_eq!(
+        v1::IpcPairs::from_slice_checked(&buffer, pairs.signature()).err(),
+        Some(DecodeError::InvalidValue { field: "Pairs.pairs.1" })
+    );
+}
+
+#[test]
+fn corrupted_tags() {
+    use core::mem::offset_of;
+
+    use flatipc::{DecodeError, IntoIpc, Ipc};
+
+    #[derive(flatipc::IpcSafe, Debug, Copy, Clone, PartialEq)]
+    #[repr(u8)]
+    pub enum Shape {
+        Dot,
+        Line(u16, bool),
+        Box { corner: u32, filled: bool },
+    }
+
+    #[derive(flatipc::IpcSafe, Debug, Copy, Clone, PartialEq)]
+    #[repr(C)]
+    pub enum CShape {
+        Dot,
+        Line(u16, bool),
+    }
+
+    #[derive(flatipc::Ipc, Debug)]
+    #[repr(C)]
+    pub struct Message {
+        count: Option<u32>,
+        flag: Option<bool>,
+        shape: Shape,
+        cshape: CShape,
+    }
+
+    let message = Message {
+        count: Some(7),
+        flag: None,
+        shape: Shape::Box { corner: 5, filled: true },
+        cshape: CShape::Line(3, true),
+    }
+    .into_ipc();
+    let signature = message.signature();
+    let buffer = flatipc::AlignedBuffer::copy_from(unsafe {
+        core::slice::from_raw_parts(&message as *const _ as *const u8, core::mem::size_of_val(&message))
+    });
+    let decoded = IpcMessage::from_slice_checked(&buffer, signature).unwrap();
+    assert_eq!((decoded.count, decoded.flag), (Some(7), None));
+    assert_eq!(
+        (decoded.shape, decoded.cshape),
+        (Shape::Box { corner: 5, filled: true }, CShape::Line(3, true))
+    );
+
+    let corrupt = |offset: usize, value: u8| {
+        let mut buffer = buffer.clone();
+        buffer[offset] = value;
+        IpcMessage::from_slice_checked(&buffer, signature).err()
+    };
+    let invalid = |field| Some(DecodeError::InvalidValue { field });
+    // The tag of `Option<u32>` precedes the value, while `None` is a niche of `bool`
+    assert_eq!(corrupt(offset_of!(Message, count), 2), invalid("Message.count"));
+    assert_eq!(corrupt(offset_of!(Message, flag), 3), invalid("Message.flag"));
+    assert_eq!(corrupt(offset_of!(Message, flag), 1), None);
+    // Tags of data enums are checked before the fields of their variant
+    assert_eq!(corrupt(offset_of!(Message, shape), 3), invalid("Message.shape"));
+    assert_eq!(corrupt(offset_of!(Message, shape) + 8, 2), invalid("Shape::Box.filled"));
+    assert_eq!(corrupt(offset_of!(Message, cshape), 9), invalid("Message.cshape"));
+    assert_eq!(corrupt(offset_of!(Message, cshape) + 6, 2), invalid("CShape::Line.1"));
+}
+
+#[test]
+fn niche_options() {
+    use flatipc::backend::mock::MockMachine;
+    use flatipc::{Dispatcher, IntoIpc, Ipc, Validate};
+
+    // The `TextView` above, less `bounds_hint`, as `TextBounds` has no `repr` and can't be validated
+    #[derive(flatipc::Ipc, Debug, Default)]
+    #[repr(C)]
+    struct TextView {
+        operation: TextOp,
+        canvas: Gid,
+        clip_rect: Option<Rectangle>,
+        untrusted: bool,
+        token: Option<[u32; 4]>,
+        invert: bool,
+        bounds_computed: Option<Rectangle>,
+        overflow: Option<bool>,
+        dry_run: bool,
+        style: GlyphStyle,
+        cursor: Cursor,
+        insertion: Option<i32>,
+        rounded_border: Option<u16>,
+        selected: Option<[u32; 2]>,
+        nested: Option<Option<Rectangle>>,
+        text: flatipc::String<64>,
+    }
+
+    let view = TextView { nested: Some(None), ..Default::default() }.into_ipc();
+    let signature = view.signature();
+    let buffer = flatipc::AlignedBuffer::copy_from(unsafe {
+        core::slice::from_raw_parts(&view as *const _ as *const u8, core::mem::size_of_val(&view))
+    });
+    assert!(IpcTextView::from_slice_checked(&buffer, signature).is_ok());
+
+    // `None` only initialises the niche of `Rectangle`, which is in one of the two
+    // `Option<PixelColor>` fields of its `DrawStyle`. The rest may hold anything.
+    let (start, len) = <Rectangle as Validate>::NICHE.unwrap();
+    let clip_rect = core::mem::offset_of!(TextView, clip_rect);
+    let mut garbage = buffer.clone();
+    for (index, byte) in
+        garbage[clip_rect..clip_rect + core::mem::size_of::<Rectangle>()].iter_mut().enumerate()
+    {
+        if !(start..start + len).contains(&index) {
+            *byte = 0xff;
+        }
+    }
+    let decoded = IpcTextView::from_slice_checked(&garbage, signature).unwrap();
+    assert!(decoded.clip_rect.is_none());
+    assert!(matches!(decoded.nested, Some(None)));
+
+    // A niche that is neither `None` nor valid is still rejected
+    garbage[clip_rect + start] = 7;
+    assert!(IpcTextView::from_slice_checked(&garbage, signature).is_err());
+
+    // `Some` is validated as usual
+    let rectangle = Rectangle {
+        style: DrawStyle { fill_color: Some(PixelColor::Light), ..Default::default() },
+        ..Default::default()
+    };
+    let view = TextView { clip_rect: Some(rectangle), ..Default::default() }.into_ipc();
+    let buffer = flatipc::AlignedBuffer::copy_from(unsafe {
+        core::slice::from_raw_parts(&view as *const _ as *const u8, core::mem::size_of_val(&view))
+    });
+    let decoded = IpcTextView::from_slice_checked(&buffer, signature).unwrap();
+    assert_eq!(decoded.clip_rect.unwrap().style.fill_color, Some(PixelColor::Light));
+
+    // A server that validates what it is lent accepts the default view
+    let machine = MockMachine::new();
+    let handle = machine.lock().unwrap().create_server();
+    let connection = machine.lock().unwrap().connect(handle.sid()).unwrap();
+    let server = std::thread::spawn(move || {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.handler::<IpcTextView>(1usize, |view: &mut TextView| view.clip_rect.is_none());
+        let mut results = vec![];
+        while let Ok(envelope) = handle.receive() {
+            results.push(dispatcher.dispatch(envelope));
+        }
+        results
+    });
+    let mut view = TextView::default().into_ipc();
+    assert_eq!(view.lend_mut_with(&machine, connection, 1usize).unwrap().offset, 1);
+    drop(machine);
+    assert_eq!(server.join().unwrap(), [Ok(())]);
+}
@@ -0,0 +1,235 @@
+//! Validation of received values.
+//!
+//! `IpcSafe` types may have bit patterns that are invalid, such as a `bool` that
+//! is neither 0 nor 1, a `char` that is not a Unicode scalar value, or an enum with
+//! an unknown discriminant. `from_slice()` trusts the sender, which is undefined
+//! behaviour if the sender is buggy or malicious. `from_slice_checked()` walks
+//! every field of the message using `Validate` before handing it to the Server.
+
+use crate::DecodeError;
+
+/// Types that can verify that a region of memory contains a valid instance of
+/// themselves. This is implemented by `#[derive(IpcSafe)]` and `#[derive(Ipc)]`,
+/// and types with no invalid bit patterns may use the default implementation.
+///
+/// # Safety
+///
+/// `validate()` must return `Ok(())` only if the memory is a valid `Self`.
+pub unsafe trait Validate {
+    /// Check that `ptr` points to a valid instance of `Self`. Errors that are
+    /// about the value as a whole rather than one of its fields have an empty
+    /// `field`, which is filled in by the type that contains the value.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned and valid for reads of `size_of::<Self>()` bytes.
+    unsafe fn validate(ptr: *const Self) -> Result<(), DecodeError> {
+        let _ = ptr;
+        Ok(())
+    }
+
+    /// Where `Option<Self>` stores `None` when it is the same size as `Self`, as the
+    /// offset and length of the scalar that holds one of the invalid values of `Self`.
+    /// Only those bytes of a `None` are initialised, and they alone decide whether an
+    /// `Option<Self>` is `None`. `Option<Self>` can't be validated if this isn't known.
+    const NICHE: Option<(usize, usize)> = None;
+}
+
+/// Validate the field `name` of a larger object, attributing any error that
+/// isn't already attributed to a nested field to `name`.
+///
+/// # Safety
+///
+/// `ptr` must be aligned and valid for reads of `size_of::<T>()` bytes.
+pub unsafe fn field<T: Validate + ?Sized>(ptr: *const T, name: &'static str) -> Result<(), DecodeError> {
+    match unsafe { T::validate(ptr) } {
+        Err(DecodeError::InvalidValue { field: "" }) => Err(DecodeError::InvalidValue { field: name }),
+        result => result,
+    }
+}
+
+/// Validate a fieldless enum by comparing its bytes against the bytes of every
+/// variant. Fieldless enums contain nothing but their discriminant, so this
+/// works regardless of their `repr`.
+///
+/// # Safety
+///
+/// `ptr` must be aligned and valid for reads of `size_of::<T>()` bytes.
+pub unsafe fn discriminant<T>(ptr: *const T, variants: &[T]) -> Result<(), DecodeError> {
+    unsafe { variant_index(ptr, variants) }.map(|_| ())
+}
+
+/// Return the index of the variant of a fieldless enum whose bytes match those
+/// at `ptr`. Enums with fields pass a fieldless copy of their tag here.
+///
+/// # Safety
+///
+/// `ptr` must be aligned and valid for reads of `size_of::<T>()` bytes.
+pub unsafe fn variant_index<T>(ptr: *const T, variants: &[T]) -> Result<usize, DecodeError> {
+    let size = core::mem::size_of::<T>();
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, size) };
+    variants
+        .iter()
+        .position(|variant| {
+            bytes == unsafe { core::slice::from_raw_parts(variant as *const T as *const u8, size) }
+        })
+        .ok_or(DecodeError::InvalidValue { field: "" })
+}
+
+/// Whether `Option<T>` stores `None` in one of the invalid values of `T`.
+pub const fn has_niche<T>() -> bool { core::mem::size_of::<Option<T>>() == core::mem::size_of::<T>() }
+
+/// Whether the field of `T` at `offset`, which is an `F`, is the one that `Option<T>`
+/// stores `None` in. An `Option<Option<T>>` that is `None` is made to hold the first
+/// `None` of `Option<F>` in that field, which turns it into `Some(None)` only if rustc
+/// reads its discriminant from that field. Evaluating this in a constant means that
+/// nothing but the discriminant is read from the partly-initialised probe.
+#[doc(hidden)]
+pub const fn holds_niche<T, F>(offset: usize) -> bool {
+    if !has_niche::<F>() {
+        return false;
+    }
+    // With only one invalid value left there's no second `None` to probe with, so
+    // every field with a niche is a candidate and `struct_niche()` needs just one.
+    if !has_niche::<Option<T>>() {
+        return true;
+    }
+    let mut probe = core::mem::MaybeUninit::<Option<Option<T>>>::uninit();
+    unsafe {
+        probe.as_mut_ptr().write(None);
+        (probe.as_mut_ptr() as *mut u8).add(offset).cast::<Option<F>>().write(None);
+        // `is_some()` would take a reference to the probe, which isn't a valid value
+        #[allow(clippy::redundant_pattern_matching)]
+        let some = matches!(*probe.as_ptr(), Some(_));
+        some
+    }
+}
+
+/// Find the niche of a struct from the offset of each field, whether it holds the
+/// niche of the struct according to `holds_niche()`, and the niche of its type.
+#[doc(hidden)]
+#[allow(clippy::type_complexity)]
+pub const fn struct_niche(fields: &[(usize, bool, Option<(usize, usize)>)]) -> Option<(usize, usize)> {
+    let mut niche = None;
+    let mut index = 0;
+    while index < fields.len() {
+        if let (offset, true, field_niche) = fields[index] {
+            if niche.is_some() {
+                return None;
+            }
+            niche = match field_niche {
+                Some((start, len)) => Some((offset + start, len)),
+                None => return None,
+            };
+        }
+        index += 1;
+    }
+    niche
+}
+
+unsafe impl Validate for i8 {}
+unsafe impl Validate for i16 {}
+unsafe impl Validate for i32 {}
+unsafe impl Validate for i64 {}
+unsafe impl Validate for i128 {}
+unsafe impl Validate for u8 {}
+unsafe impl Validate for u16 {}
+unsafe impl Validate for u32 {}
+unsafe impl Validate for u64 {}
+unsafe impl Validate for u128 {}
+unsafe impl Validate for f32 {}
+unsafe impl Validate for f64 {}
+unsafe impl Validate for usize {}
+unsafe impl Validate for isize {}
+
+unsafe impl Validate for bool {
+    const NICHE: Option<(usize, usize)> = Some((0, 1));
+
+    unsafe fn validate(ptr: *const Self) -> Result<(), DecodeError> {
+        match unsafe { (ptr as *const u8).read() } {
+            0 | 1 => Ok(()),
+            _ => Err(DecodeError::InvalidValue { field: "" }),
+        }
+    }
+}
+
+unsafe impl Validate for char {
+    const NICHE: Option<(usize, usize)> = Some((0, 4));
+
+    unsafe fn validate(ptr: *const Self) -> Result<(), DecodeError> {
+        match char::from_u32(unsafe { (ptr as *const u32).read() }) {
+            Some(_) => Ok(()),
+            None => Err(DecodeError::InvalidValue { field: "" }),
+        }
+    }
+}
+
+unsafe impl<T, const N: usize> Validate for [T; N]
+where
+    T: Validate,
+{
+    // `None` is stored in the first element
+    const NICHE: Option<(usize, usize)> = if N == 0 { None } else { T::NICHE };
+
+    unsafe fn validate(ptr: *const Self) -> Result<(), DecodeError> {
+        for i in 0..N {
+            unsafe { T::validate((ptr as *const T).add(i)) }?;
+        }
+        Ok(())
+    }
+}
+
+// The layout of `Option` is not specified, and its discriminant can't be read
+// through a reference until it is known to be valid. The bytes are instead checked
+// against the two layouts that rustc uses. If `T` has invalid values, `None` is
+// stored as one of them at `T::NICHE`, and `Option<T>` is the same size as `T`.
+// Otherwise a tag that is 0 for `None` and 1 for `Some` is followed by `T`, and is
+// as wide as the alignment of `T`. Anything else is rejected rather than guessed at.
+//
+// `Result` has no implementation, as rustc may lay out both variants around a
+// niche, which leaves no way to find its discriminant.
+unsafe impl<T> Validate for Option<T>
+where
+    T: Validate,
+{
+    // An enclosing `Option` stores `None` in the same place as this one, or in the tag
+    const NICHE: Option<(usize, usize)> = if has_niche::<T>() {
+        T::NICHE
+    } else {
+        Some((0, core::mem::size_of::<Self>() - core::mem::size_of::<T>()))
+    };
+
+    unsafe fn validate(ptr: *const Self) -> Result<(), DecodeError> {
+        let size = core::mem::size_of::<Self>();
+        let offset = size - core::mem::size_of::<T>();
+        if offset == 0 {
+            const {
+                assert!(
+                    !has_niche::<T>() || T::NICHE.is_some(),
+                    "`Option` can't be validated, as it isn't known where it stores `None`"
+                )
+            };
+            let Some((start, len)) = T::NICHE else { unreachable!() };
+            let none: Self = None;
+            let none = unsafe { core::slice::from_raw_parts((&none as *const Self as *const u8).add(start), len) };
+            if unsafe { core::slice::from_raw_parts((ptr as *const u8).add(start), len) } == none {
+                return Ok(());
+            }
+            return unsafe { T::validate(ptr as *const T) };
+        }
+        let tag = match offset {
+            _ if offset != core::mem::align_of::<T>() => None,
+            1 => Some(unsafe { (ptr as *const u8).read() } as u128),
+            2 => Some(unsafe { (ptr as *const u16).read() } as u128),
+            4 => Some(unsafe { (ptr as *const u32).read() } as u128),
+            8 => Some(unsafe { (ptr as *const u64).read() } as u128),
+            16 => Some(unsafe { (ptr as *const u128).read() }),
+            _ => None,
+        };
+        match tag {
+            Some(0) => Ok(()),
+            Some(1) => unsafe { T::validate((ptr as *const u8).add(offset) as *const T) },
+            _ => Err(DecodeError::InvalidValue { field: "" }),
+        }
+    }
+}
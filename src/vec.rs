@@ -5,7 +5,37 @@ pub struct Vec<T, const N: usize> {
     buffer: [MaybeUninit<T>; N],
 }
 
-unsafe impl<T, const N: usize> crate::IpcSafe for Vec<T, N> {}
+unsafe impl<T, const N: usize> crate::IpcSafe for Vec<T, N>
+where
+    T: crate::IpcSafe,
+{
+    const SIGNATURE: u64 = crate::signature::combine(
+        crate::signature::combine(crate::signature::fnv1a(b"flatipc::Vec"), T::SIGNATURE),
+        N as u64,
+    );
+}
+
+unsafe impl<T, const N: usize> crate::Validate for Vec<T, N>
+where
+    T: crate::Validate,
+{
+    unsafe fn validate(ptr: *const Self) -> Result<(), crate::DecodeError> {
+        let length = unsafe { core::ptr::addr_of!((*ptr).length).read() };
+        if length > N {
+            return Err(crate::DecodeError::InvalidValue { field: "" });
+        }
+        let buffer = unsafe { core::ptr::addr_of!((*ptr).buffer) } as *const T;
+        for i in 0..length {
+            unsafe { T::validate(buffer.add(i)) }?;
+        }
+        Ok(())
+    }
+}
+
+unsafe impl<T, const N: usize> crate::DefaultInPlace for Vec<T, N> {
+    // The elements beyond `length` are never read, so only the length is written
+    unsafe fn default_in_place(ptr: *mut Self) { unsafe { core::ptr::addr_of_mut!((*ptr).length).write(0) } }
+}
 
 impl<T, const N: usize> Vec<T, N> {
     pub fn new() -> Self {
@@ -35,6 +65,8 @@ impl<T, const N: usize> Vec<T, N> {
         self.length
     }
 
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
     pub fn as_slice(&self) -> &[T] {
         assert!(self.length <= self.buffer.len());
         unsafe { core::slice::from_raw_parts(self.buffer.as_ptr() as *const T, self.length) }